@@ -1,30 +1,118 @@
-use std::sync::Arc;
+use std::{fmt::Write as _, sync::Arc};
 
+use legion::system;
 use tokio::runtime::Runtime;
 use winit::{
     application::ApplicationHandler,
     dpi::PhysicalSize,
-    event::{KeyEvent, WindowEvent},
+    event::{DeviceEvent, DeviceId, Ime, KeyEvent, WindowEvent},
     event_loop::{ActiveEventLoop, ControlFlow, EventLoop},
     keyboard::PhysicalKey,
     window::{Window, WindowAttributes, WindowId},
 };
 
 use crate::{
-    audio::{audio_command_executor_system, Audio},
-    console::{console_command_postprocessor_system, console_command_preprocessor_system, Console},
-    graphics::{graphics_present_system, Graphics},
-    input::{input_command_executor_system, input_handler_system, Input, InputEvent},
-    message::{message_command_executor_system, message_handler_system, MessageSource},
+    audio::{
+        audio_command_executor_system, audio_update_system, Audio, PcmCache, SoundRegistry,
+        PCM_CACHE_DIR,
+    },
+    client::{connection_command_executor_system, connection_event_dispatcher_system, Connection},
+    console::{
+        console_command_postprocessor_system, console_command_preprocessor_system,
+        console_config_writer_system, console_input_command_executor_system, write_config_file,
+        Console, ConsoleInput, Cvar, CvarFlags,
+    },
+    graphics::{graphics_present_system, graphics_update_system, Graphics},
+    hud::Hud,
+    input::{
+        input_command_executor_system, input_handler_system, input_mouse_look_system, Input,
+        InputEvent, BUTTONS,
+    },
+    inspect::{inspector_update_system, Inspector},
+    meminfo_command_executor_system,
+    message::{
+        message_command_executor_system, message_handler_system, DemoRecorder, MessageSource,
+    },
+    resource_command_executor_system, resource_update_system,
+    screen::{
+        chat_draw_system, loading_update_system, render_stats_draw_system, screen_draw_system,
+        Loading, Screen,
+    },
+    time::{
+        time_command_executor_system, time_update_system, Clock, FrameLimiter, RenderTime,
+        SystemClock, WorldTime,
+    },
+    window::{
+        cursor_update_system, window_command_executor_system, window_icon, window_update_system,
+        WindowTitle,
+    },
     ResourceFiles,
 };
 
+// Joins the command line arguments into console commands, every argument starting with a '+' starts a new command,
+// like "+playdemo demo1 +echo done" does.
+#[must_use]
+pub fn command_line<I: IntoIterator<Item = String>>(args: I) -> String {
+    let mut commands = String::new();
+    for arg in args {
+        if let Some(command) = arg.strip_prefix('+') {
+            if !commands.is_empty() {
+                commands.push(';');
+            }
+            commands.push_str(command);
+        } else if !commands.is_empty() {
+            let _ = write!(commands, " \"{arg}\"");
+        }
+    }
+
+    commands
+}
+
+// State of the client itself, as opposed to the game it runs.
+#[derive(Default)]
+pub struct Host {
+    quit: bool,
+}
+
+impl Host {
+    #[must_use]
+    pub const fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+// Frame rate asked for by `host_maxfps`, 0 when unlimited.
+fn max_fps(shared_resources: &legion::Resources) -> f32 {
+    shared_resources
+        .get::<Console>()
+        .and_then(|console| console.get_var::<f32>("host_maxfps"))
+        .unwrap_or(0.0)
+}
+
+#[system]
+pub fn host_command_executor(#[resource] host: &mut Host, #[resource] console: &Console) {
+    // Exits the game once the frame is over.
+    if console.commands().any(|command| command[0] == "quit") {
+        host.quit = true;
+    }
+}
+
 #[derive(Default)]
 pub struct GameApp {
     inner: Option<InnerApp>,
+    // Console commands run after quake.rc.
+    commands: String,
 }
 
 impl GameApp {
+    #[must_use]
+    pub fn new(commands: &str) -> Self {
+        Self {
+            inner: None,
+            commands: commands.to_owned(),
+        }
+    }
+
     pub fn run_app(&mut self) -> anyhow::Result<()> {
         let event_loop = EventLoop::new()?;
         event_loop.set_control_flow(ControlFlow::Poll);
@@ -37,7 +125,10 @@ impl GameApp {
 impl ApplicationHandler for GameApp {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
         let rt = Runtime::new().unwrap();
-        self.inner = Some(rt.block_on(InnerApp::new(event_loop)).unwrap());
+        self.inner = Some(
+            rt.block_on(InnerApp::new(event_loop, &self.commands))
+                .unwrap(),
+        );
     }
 
     fn window_event(
@@ -49,8 +140,18 @@ impl ApplicationHandler for GameApp {
         self.inner
             .as_mut()
             .unwrap()
-            .window_event(event_loop, event)
-            .unwrap();
+            .window_event(event_loop, &event);
+    }
+
+    fn device_event(
+        &mut self,
+        _event_loop: &ActiveEventLoop,
+        _device_id: DeviceId,
+        event: DeviceEvent,
+    ) {
+        if let Some(inner) = &self.inner {
+            inner.device_event(&event);
+        }
     }
 }
 
@@ -62,91 +163,428 @@ struct InnerApp {
     entity_world: legion::World,
     shared_resources: legion::Resources,
     system_schedule: legion::Schedule,
+    frame_limiter: FrameLimiter,
 }
 
 impl InnerApp {
-    async fn new(event_loop: &ActiveEventLoop) -> anyhow::Result<Self> {
-        let window_size = PhysicalSize::new(2048, 1080);
+    async fn new(event_loop: &ActiveEventLoop, commands: &str) -> anyhow::Result<Self> {
+        let entity_world = legion::World::default();
+        let mut shared_resources = legion::Resources::default();
+        let system_schedule = Self::new_schedule(false);
+
+        // Everything that measures real time reads the same clock.
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::default());
+
+        let resource_files = ResourceFiles::new("res/")?;
+        Self::insert_resources(
+            &mut shared_resources,
+            resource_files,
+            Arc::clone(&clock),
+            commands,
+        );
+
+        // The configuration is only queued at this point, so the window opens at the default size of the video
+        // variables.  The video mode of the configuration is set by the vid_restart queued after it.
+        let window_size = {
+            let console = shared_resources.get::<Console>().unwrap();
+            PhysicalSize::new(
+                console.get_var::<u32>("vid_width").unwrap_or(0).max(1),
+                console.get_var::<u32>("vid_height").unwrap_or(0).max(1),
+            )
+        };
         let window_attributes = WindowAttributes::default()
-            .with_decorations(false)
             .with_inner_size(window_size)
-            .with_resizable(false);
+            .with_resizable(true);
         let window = Arc::new(event_loop.create_window(window_attributes)?);
+        let window_size = window.inner_size();
 
         let (_output_stream, output_stream_handle) = rodio::OutputStream::try_default()?;
 
-        let entity_world = legion::World::default();
-        let mut shared_resources = legion::Resources::default();
-        let system_schedule = legion::Schedule::builder()
+        let mut graphics = Graphics::new(
+            Arc::clone(&window),
+            window_size.width.max(1),
+            window_size.height.max(1),
+        )
+        .await?;
+
+        let (audio, screen) = {
+            let resource_files = shared_resources.get::<ResourceFiles>().unwrap();
+            match window_icon(&resource_files) {
+                Ok(icon) => window.set_window_icon(Some(icon)),
+                Err(e) => tracing::warn!("couldn't load window icon: {:#}", e),
+            }
+
+            let pcm_cache = PcmCache::new(resource_files.dir_path().join(PCM_CACHE_DIR));
+            let audio = Audio::new(output_stream_handle, pcm_cache, Arc::clone(&clock))?;
+            (audio, Screen::new(&mut graphics, &resource_files))
+        };
+        shared_resources.insert(audio);
+        shared_resources.insert(screen);
+        shared_resources.insert(Arc::clone(&window));
+        shared_resources.insert(WindowTitle::default());
+        shared_resources.insert(graphics);
+        shared_resources.insert(Inspector::default());
+        shared_resources
+            .get_mut::<Console>()
+            .unwrap()
+            .push_command("vid_restart");
+
+        Ok(Self {
+            window,
+
+            _output_stream,
+
+            entity_world,
+            shared_resources,
+            system_schedule,
+            frame_limiter: FrameLimiter::new(clock),
+        })
+    }
+
+    // Without a window there is nothing to draw, play or inspect, only the systems that run the console, the
+    // messages and the clocks are scheduled.
+    fn new_schedule(headless: bool) -> legion::Schedule {
+        let mut builder = legion::Schedule::builder();
+        builder
+            .add_system(time_update_system())
+            .add_system(loading_update_system())
+            .add_system(resource_update_system())
+            .flush()
             .add_system(message_handler_system())
+            .add_system(connection_event_dispatcher_system())
             .add_system(input_handler_system())
+            .add_system(input_mouse_look_system())
             .flush()
             .add_system(console_command_preprocessor_system())
             .flush()
-            .add_system(audio_command_executor_system())
             .add_system(input_command_executor_system())
             .add_system(message_command_executor_system())
+            .add_system(console_config_writer_system())
+            .add_system(console_input_command_executor_system())
+            .add_system(time_command_executor_system())
+            .add_system(resource_command_executor_system())
+            .add_system(host_command_executor_system())
+            .add_system(connection_command_executor_system());
+        if !headless {
+            builder
+                .add_system(audio_command_executor_system())
+                .add_system(audio_update_system())
+                .add_system(meminfo_command_executor_system())
+                .add_system(inspector_update_system())
+                .add_system(graphics_update_system())
+                .add_system(window_command_executor_system());
+        }
+        builder
             .flush()
             .add_system(console_command_postprocessor_system())
-            .flush()
-            .add_system(graphics_present_system())
-            .build();
-
-        let audio = Audio::new(output_stream_handle)?;
-        shared_resources.insert(audio);
+            .flush();
+        if !headless {
+            builder
+                .add_system(screen_draw_system())
+                .add_system(chat_draw_system())
+                .add_system(render_stats_draw_system())
+                .add_system(window_update_system())
+                .add_system(cursor_update_system(None))
+                .flush()
+                .add_system(graphics_present_system());
+        }
 
-        let graphics =
-            Graphics::new(Arc::clone(&window), window_size.width, window_size.height).await?;
-        shared_resources.insert(graphics);
+        builder.build()
+    }
 
-        let resource_files = ResourceFiles::new("res/")?;
+    // Resources shared by the windowed and the headless clients.  The commands run after quake.rc.
+    fn insert_resources(
+        shared_resources: &mut legion::Resources,
+        resource_files: ResourceFiles,
+        clock: Arc<dyn Clock>,
+        commands: &str,
+    ) {
         shared_resources.insert(resource_files);
 
+        let mut console = Self::new_console();
+        console.push_command("exec quake.rc");
+        console.push_command(commands);
+        shared_resources.insert(console);
+        shared_resources.insert(ConsoleInput::default());
+
+        let input = Input::default();
+        shared_resources.insert(input);
+
+        let input_events: Vec<InputEvent> = Vec::new();
+        shared_resources.insert(input_events);
+
+        let message_stream: Option<MessageSource> = None;
+        shared_resources.insert(message_stream);
+        shared_resources.insert(DemoRecorder::default());
+
+        shared_resources.insert(RenderTime::new(clock));
+        shared_resources.insert(WorldTime::default());
+
+        let loading: Option<Loading> = None;
+        shared_resources.insert(loading);
+
+        shared_resources.insert(Connection::default());
+        shared_resources.insert(SoundRegistry::default());
+        shared_resources.insert(Hud::default());
+        shared_resources.insert(Host::default());
+    }
+
+    // Console with every command and variable of the client registered.
+    #[allow(clippy::too_many_lines)]
+    fn new_console() -> Console {
         let mut console = Console::default();
         console.register_command("cd");
+        console.register_command("music");
+        console.register_command("music_pause");
+        console.register_command("music_resume");
+        console.register_command("music_stop");
         console.register_command("play");
+        console.register_command("snd_cachepurge");
 
         console.register_command("exec");
         console.register_command("alias");
+        console.register_command("on");
+        console.register_command("set");
+        console.register_command("toggle");
+        console.register_command("inc");
+        console.register_command("cycle");
+        console.register_command("cmdlist");
+        console.register_command("cvarlist");
+        console.register_command("echo");
+        console.register_command("clear");
+        console.register_command("toggleconsole");
+        console.register_command("quit");
+        console.register_command("search");
+        console.register_command("host_writeconfig");
+        console.register_command("pause");
+        console.register_command("path");
+        console.register_command("which");
+        console.register_command("meminfo");
+        console.register_command("vid_restart");
 
+        for button in BUTTONS {
+            console.register_command(&format!("+{button}"));
+            console.register_command(&format!("-{button}"));
+        }
+        console.register_command("messagemode");
+        console.register_command("messagemode2");
+        console.register_command("say");
+        console.register_command("say_team");
         console.register_command("bind");
         console.register_command("unbind");
         console.register_command("unbindall");
+        console.register_command("bindlist");
 
         console.register_command("playdemo");
         console.register_command("stopdemo");
         console.register_command("startdemos");
+        console.register_command("record");
+        console.register_command("stop");
 
-        console.push_command("exec quake.rc");
-        shared_resources.insert(console);
-
-        let input = Input::default();
-        shared_resources.insert(input);
+        console.register_variable(
+            "sv_cheats",
+            Cvar::new(
+                "0",
+                "Allows cheat protected variables to be changed.",
+                CvarFlags::SERVERINFO,
+            ),
+        );
+        console.register_variable(
+            "host_timescale",
+            Cvar::new(
+                "1",
+                "Speed of the world clock, used for slow motion and fast forward.",
+                CvarFlags::CHEAT,
+            ),
+        );
+        console.register_variable(
+            "con_notifyfilter",
+            Cvar::new(
+                "",
+                "Only the notify lines containing this text are shown, all of them when empty.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "host_titleinfo",
+            Cvar::new(
+                "0",
+                "Shows the level name and the frame rate in the window title.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "host_inspectport",
+            Cvar::new(
+                "0",
+                "Local TCP port serving JSON snapshots of the client state to external tools, 0 disables it.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "gamma",
+            Cvar::new(
+                "1",
+                "Brightness of the screen, from 0.5 for the brightest to 1 for the original colors.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "contrast",
+            Cvar::new(
+                "1",
+                "Contrast of the screen, from 1 for the original colors to 2.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "volume",
+            Cvar::new(
+                "0.7",
+                "Volume of the sound effects, from 0 for silence to 1.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "bgmvolume",
+            Cvar::new(
+                "1",
+                "Volume of the background music, from 0 for silence to 1.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "music_volume",
+            Cvar::new(
+                "1",
+                "Volume of the music tracks, from 0 for silence to 1, scaled by bgmvolume.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "sensitivity",
+            Cvar::new(
+                "3",
+                "Speed the mouse turns the view at.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "m_pitch",
+            Cvar::new(
+                "0.022",
+                "Degrees the view is pitched by per unit of mouse movement, negative to invert the mouse.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "m_yaw",
+            Cvar::new(
+                "0.022",
+                "Degrees the view is turned by per unit of mouse movement.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "m_filter",
+            Cvar::new(
+                "0",
+                "Averages the mouse movement of the last two frames to smooth it out.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "vid_width",
+            Cvar::new(
+                "2048",
+                "Width of the window or of the fullscreen mode, applied by vid_restart.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "vid_height",
+            Cvar::new(
+                "1080",
+                "Height of the window or of the fullscreen mode, applied by vid_restart.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "vid_fullscreen",
+            Cvar::new(
+                "0",
+                "0 for a window, 1 for borderless fullscreen and 2 for exclusive fullscreen, applied by vid_restart.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "vid_vsync",
+            Cvar::new(
+                "1",
+                "Waits for the vertical blank to present frames, 0 presents them as soon as they are done.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "host_maxfps",
+            Cvar::new(
+                "0",
+                "Highest frame rate the client runs at, 0 leaves it unlimited.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "cl_showfps",
+            Cvar::new(
+                "0",
+                "Shows the frame rate in the top right corner of the screen.",
+                CvarFlags::ARCHIVE,
+            ),
+        );
+        console.register_variable(
+            "r_speeds",
+            Cvar::new(
+                "0",
+                "Shows the frame time and the work done by the renderer every frame.",
+                CvarFlags::empty(),
+            ),
+        );
 
-        let input_event: Option<InputEvent> = None;
-        shared_resources.insert(input_event);
-
-        let message_stream: Option<MessageSource> = None;
-        shared_resources.insert(message_stream);
+        console
+    }
 
-        Ok(Self {
-            window,
+    // Key bindings and console variables are kept for the next session.  A configuration that cannot be written,
+    // such as on a read-only or full disk, doesn't keep the client from exiting.
+    fn shutdown(shared_resources: &legion::Resources) {
+        let console = shared_resources.get::<Console>().unwrap();
+        let input = shared_resources.get::<Input>().unwrap();
+        let resource_files = shared_resources.get::<ResourceFiles>().unwrap();
+        if let Err(e) = write_config_file(&console, &input, &resource_files) {
+            tracing::warn!("couldn't write the configuration: {:#}", e);
+        }
+    }
 
-            _output_stream,
+    // Every event of the frame is handled, so that a key pressed and released between two frames isn't missed.
+    fn push_input_event(&self, input_event: InputEvent) {
+        self.shared_resources
+            .get_mut::<Vec<InputEvent>>()
+            .unwrap()
+            .push(input_event);
+    }
 
-            entity_world,
-            shared_resources,
-            system_schedule,
-        })
+    // Raw mouse motion is used for mouse look, unlike the cursor position it isn't held back by the window borders.
+    fn device_event(&self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (dx, dy) } = *event {
+            if let Some(mut input) = self.shared_resources.get_mut::<Input>() {
+                input.add_mouse_motion(dx, dy);
+            }
+        }
     }
 
-    fn window_event(
-        &mut self,
-        event_loop: &ActiveEventLoop,
-        event: WindowEvent,
-    ) -> anyhow::Result<()> {
-        match event {
+    fn window_event(&mut self, event_loop: &ActiveEventLoop, event: &WindowEvent) {
+        match *event {
             WindowEvent::CloseRequested => {
+                Self::shutdown(&self.shared_resources);
                 event_loop.exit();
             }
             WindowEvent::KeyboardInput {
@@ -154,32 +592,108 @@ impl InnerApp {
                     KeyEvent {
                         physical_key: PhysicalKey::Code(code),
                         state,
+                        ref text,
                         ..
                     },
                 ..
             } => {
-                let input_event = InputEvent::KeyboardInput { code, state };
-                self.shared_resources.insert(Some(input_event));
+                let text = text.as_ref().and_then(|text| text.chars().next());
+                self.push_input_event(InputEvent::KeyboardInput { code, state, text });
             }
             WindowEvent::MouseInput { state, button, .. } => {
-                let input_event = InputEvent::MouseInput { state, button };
-                self.shared_resources.insert(Some(input_event));
+                self.push_input_event(InputEvent::MouseInput { state, button });
+            }
+            WindowEvent::Resized(size) => {
+                if let Some(mut graphics) = self.shared_resources.get_mut::<Graphics>() {
+                    graphics.resize(size.width, size.height);
+                }
+            }
+            WindowEvent::Ime(Ime::Commit(ref text)) => {
+                for text in text.chars() {
+                    self.push_input_event(InputEvent::Text { text });
+                }
             }
             WindowEvent::MouseWheel { delta, .. } => {
-                let input_event = InputEvent::MouseWheel { delta };
-                self.shared_resources.insert(Some(input_event));
+                self.push_input_event(InputEvent::MouseWheel { delta });
             }
             WindowEvent::RedrawRequested => {
                 self.system_schedule
                     .execute(&mut self.entity_world, &mut self.shared_resources);
 
-                let input_event: Option<InputEvent> = None;
-                self.shared_resources.insert(input_event);
+                self.shared_resources
+                    .get_mut::<Vec<InputEvent>>()
+                    .unwrap()
+                    .clear();
+
+                if self
+                    .shared_resources
+                    .get::<Host>()
+                    .unwrap()
+                    .quit_requested()
+                {
+                    Self::shutdown(&self.shared_resources);
+                    event_loop.exit();
+                }
+                self.frame_limiter.wait(max_fps(&self.shared_resources));
             }
             _ => (),
         }
         self.window.request_redraw();
+    }
+}
 
-        Ok(())
+// Frame rate of the headless client when `host_maxfps` leaves it unlimited, the rate the original server runs at.
+const HEADLESS_MAX_FPS: f32 = 72.0;
+
+// Client without a window, renderer or audio output.  It runs the console, the messages and the clocks, driven by
+// the commands given to it, until a quit command.
+pub struct HeadlessApp {
+    entity_world: legion::World,
+    shared_resources: legion::Resources,
+    system_schedule: legion::Schedule,
+    frame_limiter: FrameLimiter,
+}
+
+impl HeadlessApp {
+    pub fn new(commands: &str) -> anyhow::Result<Self> {
+        let mut shared_resources = legion::Resources::default();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::default());
+        let resource_files = ResourceFiles::new("res/")?;
+        InnerApp::insert_resources(
+            &mut shared_resources,
+            resource_files,
+            Arc::clone(&clock),
+            commands,
+        );
+
+        Ok(Self {
+            entity_world: legion::World::default(),
+            shared_resources,
+            system_schedule: InnerApp::new_schedule(true),
+            frame_limiter: FrameLimiter::new(clock),
+        })
+    }
+
+    pub fn run_app(&mut self) -> anyhow::Result<()> {
+        loop {
+            self.system_schedule
+                .execute(&mut self.entity_world, &mut self.shared_resources);
+
+            if self
+                .shared_resources
+                .get::<Host>()
+                .unwrap()
+                .quit_requested()
+            {
+                InnerApp::shutdown(&self.shared_resources);
+                return Ok(());
+            }
+            let max_fps = max_fps(&self.shared_resources);
+            self.frame_limiter.wait(if max_fps > 0.0 {
+                max_fps
+            } else {
+                HEADLESS_MAX_FPS
+            });
+        }
     }
 }
@@ -1,122 +1,898 @@
 use std::{
-    io::{Read, Seek},
-    path::PathBuf,
-    str::FromStr,
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Cursor, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use legion::system;
-use rodio::{Decoder, OutputStreamHandle, Sink, Source};
+use rodio::{
+    buffer::SamplesBuffer, source::ChannelVolume, Decoder, OutputStreamHandle, Sink, Source,
+};
+use winit::window::Window;
 
 use crate::{
+    client::{Connection, ConnectionState},
     console::{Console, ConsoleCmd},
-    ResourceFiles,
+    message::ServerMessage,
+    time::{Clock, WorldTime},
+    ResourceBundle, ResourceFiles, ResourceFuture,
 };
 
+// Decoded samples of a sound effect.
+pub struct Pcm {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl Pcm {
+    pub fn decode<R>(data: R) -> anyhow::Result<Self>
+    where
+        R: Read + Seek + Send + Sync + 'static,
+    {
+        let decoder = Decoder::new(data)?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let samples = decoder.collect();
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples,
+        })
+    }
+
+    fn read<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut ident = [0u8; 4];
+        reader.read_exact(&mut ident)?;
+        if &ident != PCM_CACHE_IDENT {
+            bail!("invalid signature");
+        }
+
+        let channels = reader.read_u16::<LittleEndian>()?;
+        let sample_rate = reader.read_u32::<LittleEndian>()?;
+        let sample_count = reader.read_u32::<LittleEndian>()?;
+        // The samples are read as far as the file goes before the count is trusted.
+        let length = u64::from(sample_count) * 2;
+        let mut buf = Vec::new();
+        reader.take(length).read_to_end(&mut buf)?;
+        if buf.len() != usize::try_from(length)? {
+            bail!("unexpected end of file");
+        }
+        let samples = buf
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+
+        Ok(Self {
+            channels,
+            sample_rate,
+            samples,
+        })
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> anyhow::Result<()> {
+        writer.write_all(PCM_CACHE_IDENT)?;
+        writer.write_u16::<LittleEndian>(self.channels)?;
+        writer.write_u32::<LittleEndian>(self.sample_rate)?;
+        writer.write_u32::<LittleEndian>(u32::try_from(self.samples.len())?)?;
+        for sample in &self.samples {
+            writer.write_i16::<LittleEndian>(*sample)?;
+        }
+
+        Ok(())
+    }
+
+    fn to_source(&self) -> SamplesBuffer<i16> {
+        SamplesBuffer::new(self.channels, self.sample_rate, self.samples.clone())
+    }
+}
+
+const PCM_CACHE_IDENT: &[u8; 4] = b"QPCM";
+
+// Decoded sound effects kept on disk, keyed by a hash of the encoded file so that an edited sound is decoded again.
+#[derive(Clone)]
+pub struct PcmCache {
+    dir_path: PathBuf,
+}
+
+impl PcmCache {
+    pub fn new<P: AsRef<Path>>(dir_path: P) -> Self {
+        Self {
+            dir_path: dir_path.as_ref().to_path_buf(),
+        }
+    }
+
+    // Returns the decoded samples from the cache, decoding and storing them on a miss.  A cache that cannot be
+    // written only costs the decoding time, so those errors are logged and ignored.
+    pub fn load<R>(&self, mut data: R) -> anyhow::Result<Pcm>
+    where
+        R: Read,
+    {
+        let mut buf = Vec::new();
+        data.read_to_end(&mut buf)?;
+        let file_path = self.dir_path.join(format!("{:016x}.pcm", Self::hash(&buf)));
+
+        if let Ok(file) = File::open(&file_path) {
+            match Pcm::read(&mut BufReader::new(file)) {
+                Ok(pcm) => return Ok(pcm),
+                Err(e) => tracing::warn!("invalid cache entry {}: {:#}", file_path.display(), e),
+            }
+        }
+
+        let pcm = Pcm::decode(Cursor::new(buf))?;
+        if let Err(e) = self.store(&file_path, &pcm) {
+            tracing::warn!(
+                "couldn't write cache entry {}: {:#}",
+                file_path.display(),
+                e
+            );
+        }
+
+        Ok(pcm)
+    }
+
+    pub fn purge(&self) -> anyhow::Result<()> {
+        if self.dir_path.is_dir() {
+            fs::remove_dir_all(&self.dir_path)?;
+        }
+
+        Ok(())
+    }
+
+    // Bytes taken by the cache entries.
+    pub fn disk_usage(&self) -> anyhow::Result<u64> {
+        if !self.dir_path.is_dir() {
+            return Ok(0);
+        }
+
+        let mut bytes = 0;
+        for entry in fs::read_dir(&self.dir_path)? {
+            bytes += entry?.metadata()?.len();
+        }
+
+        Ok(bytes)
+    }
+
+    fn store(&self, file_path: &Path, pcm: &Pcm) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir_path)?;
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        pcm.write(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // 64-bit FNV-1a, stable across builds unlike the standard library hasher.
+    fn hash(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+// Distance at which a sound with an attenuation of 1 fades out completely.
+const SOUND_NOMINAL_CLIP_DIST: f32 = 1000.0;
+// Channels of the original engine, the sound effects and the static sounds together.
+const MAX_CHANNELS: usize = 128;
+const DEFAULT_SFX_VOLUME: f32 = 0.7;
+// Formats the music tracks are looked up in, by order of preference.
+const MUSIC_EXTENSIONS: [&str; 3] = ["ogg", "mp3", "wav"];
+// How often a playing sound picks up the gains computed for the listener.
+const SPATIALIZE_PERIOD: Duration = Duration::from_millis(10);
+
+// Point of view the sounds are heard from.
+#[derive(Default)]
+struct Listener {
+    entity: u16,
+    origin: [f32; 3],
+    // Direction of the right ear, derived from the view angles.
+    right: [f32; 3],
+}
+
+impl Listener {
+    // The volume fades linearly with the distance and is panned between the ears by the side the sound comes from.
+    fn gains(&self, emitter: &Emitter) -> [f32; 2] {
+        if emitter.entity == self.entity {
+            return [emitter.volume; 2];
+        }
+
+        let delta: [f32; 3] = std::array::from_fn(|i| emitter.origin[i] - self.origin[i]);
+        let distance = delta.iter().map(|d| d * d).sum::<f32>().sqrt();
+        let dot = if distance > 0.0 {
+            (0..3).map(|i| self.right[i] * delta[i]).sum::<f32>() / distance
+        } else {
+            0.0
+        };
+        let fade = 1.0 - distance * emitter.attenuation / SOUND_NOMINAL_CLIP_DIST;
+
+        [
+            (emitter.volume * fade * (1.0 - dot)).max(0.0),
+            (emitter.volume * fade * (1.0 + dot)).max(0.0),
+        ]
+    }
+
+    // Whether the emitter is closer than the distance its sound fades out at.
+    fn hears(&self, emitter: &Emitter) -> bool {
+        let distance_squared = (0..3)
+            .map(|i| (emitter.origin[i] - self.origin[i]).powi(2))
+            .sum::<f32>();
+        emitter.entity == self.entity
+            || distance_squared * emitter.attenuation.powi(2)
+                < SOUND_NOMINAL_CLIP_DIST * SOUND_NOMINAL_CLIP_DIST
+    }
+}
+
+// Sound played from a point of the world.  Sounds are addressed by the entity and the channel of the entity they are
+// played on, the auto channel 0 never replaces a playing sound.
+#[derive(Clone, Copy)]
+struct Emitter {
+    entity: u16,
+    channel: u8,
+    origin: [f32; 3],
+    volume: f32,
+    attenuation: f32,
+}
+
+struct Channel {
+    sink: Sink,
+    // Left and right gains the playing sound picks up periodically.
+    gains: Arc<Mutex<[f32; 2]>>,
+    // Sounds without an emitter, such as the music or the menu sounds, are heard at full volume.
+    emitter: Option<Emitter>,
+    // Time of the audio clock at which the sound played from the emitter is over.
+    ends: Duration,
+    // Paused along with the whole audio, as opposed to paused on its own like the music can be.
+    suspended: bool,
+    // Static sound paused while the listener is too far to hear it, so that it isn't mixed.
+    muted: bool,
+}
+
+impl Channel {
+    fn new(output_stream_handle: &OutputStreamHandle) -> anyhow::Result<Self> {
+        Ok(Self {
+            sink: Sink::try_new(output_stream_handle)?,
+            gains: Arc::new(Mutex::new([1.0; 2])),
+            emitter: None,
+            ends: Duration::ZERO,
+            suspended: false,
+            muted: false,
+        })
+    }
+
+    // Plays the source mixed down to mono and panned with the gains, which are picked up again while it plays.
+    fn play_emitter<S>(&mut self, source: S, emitter: Emitter, gains: [f32; 2], now: Duration)
+    where
+        S: Source<Item = i16> + Send + 'static,
+    {
+        *self.gains.lock().unwrap() = gains;
+        self.emitter = Some(emitter);
+        self.ends = now + source.total_duration().unwrap_or_default();
+
+        let shared_gains = Arc::clone(&self.gains);
+        let source = ChannelVolume::new(source, gains.to_vec()).periodic_access(
+            SPATIALIZE_PERIOD,
+            move |source| {
+                let [left, right] = *shared_gains.lock().unwrap();
+                source.set_volume(0, left);
+                source.set_volume(1, right);
+            },
+        );
+        self.sink.clear();
+        self.sink.append(source);
+        self.sink.play();
+    }
+}
+
+// Directory of the resources the decoded sounds are cached in.
+pub const PCM_CACHE_DIR: &str = "cache/sound";
+
+// Sounds precached by the server, with the ids of its sound table: they follow the order of the precache list
+// starting at 1, as 0 means no sound.  The sounds are decoded by the resource workers while the level loads.
+#[derive(Default)]
+pub struct SoundRegistry {
+    names: Box<[String]>,
+    ids: HashMap<String, u8>,
+    // Sounds still being decoded.
+    bundle: Option<ResourceBundle<Pcm>>,
+    // Decoded sounds, none for those that couldn't be loaded or are not in the table so that they are reported once.
+    sounds: HashMap<u8, Option<Pcm>>,
+}
+
+impl SoundRegistry {
+    // A name precached twice keeps its first id, names past the last id are reported and left out.
+    #[must_use]
+    pub fn load(
+        level_name: &str,
+        precache_sounds: &[String],
+        resource_files: &ResourceFiles,
+    ) -> Self {
+        let max_sounds = usize::from(u8::MAX);
+        if precache_sounds.len() > max_sounds {
+            tracing::warn!(
+                "{} sounds precached, only the first {} can be played",
+                precache_sounds.len(),
+                max_sounds
+            );
+        }
+
+        let names: Box<[String]> = precache_sounds.iter().take(max_sounds).cloned().collect();
+        let mut ids = HashMap::new();
+        for (id, name) in (1..=u8::MAX).zip(names.iter()) {
+            ids.entry(name.clone()).or_insert(id);
+        }
+
+        let pcm_cache = PcmCache::new(resource_files.dir_path().join(PCM_CACHE_DIR));
+        let bundle = resource_files.load_bundle_with(
+            level_name,
+            names.iter().map(|name| Path::new("sound").join(name)),
+            move |data| pcm_cache.load(data),
+        );
+
+        Self {
+            names,
+            ids,
+            bundle: Some(bundle),
+            sounds: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    #[must_use]
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).copied()
+    }
+
+    #[must_use]
+    pub fn name(&self, id: u8) -> Option<&str> {
+        let index = usize::from(id).checked_sub(1)?;
+        self.names.get(index).map(String::as_str)
+    }
+
+    // Fraction of the sounds already decoded or failed.
+    #[must_use]
+    pub fn progress(&self) -> f32 {
+        self.bundle.as_ref().map_or(1.0, ResourceBundle::progress)
+    }
+
+    #[must_use]
+    pub const fn is_complete(&self) -> bool {
+        self.bundle.is_none()
+    }
+
+    // Bytes taken by the decoded samples.
+    #[must_use]
+    pub fn loaded_bytes(&self) -> usize {
+        self.sounds
+            .values()
+            .flatten()
+            .map(|pcm| pcm.samples.len() * size_of::<i16>())
+            .sum()
+    }
+
+    // Collects the sounds decoded since the last call, the sounds that couldn't be loaded are reported once all of
+    // them are done.
+    pub fn poll(&mut self) {
+        let Some(bundle) = &mut self.bundle else {
+            return;
+        };
+
+        bundle.poll();
+        for (name, id) in &self.ids {
+            if let Some(pcm) = bundle.take(Path::new("sound").join(name)) {
+                self.sounds.insert(*id, Some(pcm));
+            }
+        }
+        if bundle.is_complete() {
+            bundle.errors().for_each(|(file_path, e)| {
+                tracing::warn!("couldn't load {}: {:#}", file_path.display(), e);
+            });
+            self.bundle = None;
+        }
+    }
+
+    // Ids outside of the sound table come from a server or a demo that doesn't match the precache list, they are
+    // reported once.  The ids of a name precached twice share the sound of the first one.
+    fn sound(&mut self, id: u8) -> Option<SamplesBuffer<i16>> {
+        let id = self.name(id).and_then(|name| self.id(name)).unwrap_or(id);
+        if let Some(pcm) = self.sounds.get(&id) {
+            return pcm.as_ref().map(Pcm::to_source);
+        }
+
+        if self.name(id).is_none() {
+            tracing::warn!(
+                "sound {} is not in the table of {} precached sounds",
+                id,
+                self.len()
+            );
+        }
+        self.sounds.insert(id, None);
+        None
+    }
+}
+
+// Sound output of the client.  The level sounds are decoded while the level loads, the music and the sounds of the
+// play command are read and decoded by the resource workers when requested and started by `audio_update` once they
+// are, so that no request holds the frame.  Mixing runs on the output stream thread.
 pub struct Audio {
-    channels: Box<[Sink]>,
+    output_stream_handle: OutputStreamHandle,
+    channels: Box<[Channel]>,
+    // Looping sounds placed in the level, they play until the level changes.
+    static_channels: Vec<Channel>,
+    pcm_cache: PcmCache,
+    clock: Arc<dyn Clock>,
+    listener: Listener,
+    // Sounds played with the play command.
+    pending_local_sounds: Vec<ResourceFuture<Pcm>>,
+    // Music track being read, with whether it loops.
+    pending_music: Option<(ResourceFuture<Vec<u8>>, bool)>,
+    // Volume of the sound effects, it matches the default of the volume variable until that is set.
+    sfx_volume: f32,
 }
 
 impl Audio {
-    pub fn new(output_stream_handle: OutputStreamHandle) -> anyhow::Result<Self> {
+    pub fn new(
+        output_stream_handle: OutputStreamHandle,
+        pcm_cache: PcmCache,
+        clock: Arc<dyn Clock>,
+    ) -> anyhow::Result<Self> {
         let mut channels = Vec::new();
-        for _ in 0..32 {
-            let channel = Sink::try_new(&output_stream_handle)?;
+        for i in 0..32 {
+            let channel = Channel::new(&output_stream_handle)?;
+            if i > 0 {
+                channel.sink.set_volume(DEFAULT_SFX_VOLUME);
+            }
             channels.push(channel);
         }
 
         Ok(Self {
+            output_stream_handle,
             channels: channels.into_boxed_slice(),
+            static_channels: Vec::new(),
+            pcm_cache,
+            clock,
+            listener: Listener::default(),
+            pending_local_sounds: Vec::new(),
+            pending_music: None,
+            sfx_volume: DEFAULT_SFX_VOLUME,
         })
     }
 
-    pub fn play_channel<R>(&self, channel: usize, data: R) -> anyhow::Result<()>
+    #[must_use]
+    pub const fn pcm_cache(&self) -> &PcmCache {
+        &self.pcm_cache
+    }
+
+    #[must_use]
+    pub fn busy_channels(&self) -> usize {
+        self.channels
+            .iter()
+            .filter(|channel| !channel.sink.empty())
+            .count()
+    }
+
+    pub fn play_pcm(&mut self, channel: usize, pcm: &Pcm) {
+        let channel = &mut self.channels[channel];
+        channel.emitter = None;
+        channel.sink.clear();
+        channel.sink.append(pcm.to_source());
+        channel.sink.play();
+    }
+
+    pub fn play_channel<R>(&mut self, channel: usize, data: R) -> anyhow::Result<()>
     where
         R: Read + Seek + Send + Sync + 'static,
     {
         let source = Decoder::new(data)?;
 
-        self.channels[channel].clear();
-        self.channels[channel].append(source);
-        self.channels[channel].play();
+        let channel = &mut self.channels[channel];
+        channel.emitter = None;
+        channel.sink.clear();
+        channel.sink.append(source);
+        channel.sink.play();
 
         Ok(())
     }
 
-    pub fn loop_channel<R>(&self, channel: usize, data: R) -> anyhow::Result<()>
+    pub fn loop_channel<R>(&mut self, channel: usize, data: R) -> anyhow::Result<()>
     where
         R: Read + Seek + Send + Sync + 'static,
     {
         let source = Decoder::new(data)?.repeat_infinite();
 
-        self.channels[channel].clear();
-        self.channels[channel].append(source);
-        self.channels[channel].play();
+        let channel = &mut self.channels[channel];
+        channel.emitter = None;
+        channel.sink.clear();
+        channel.sink.append(source);
+        channel.sink.play();
 
         Ok(())
     }
 
     pub fn pause_channel(&self, channel: usize) {
-        self.channels[channel].pause();
+        self.channels[channel].sink.pause();
     }
 
     pub fn resume_channel(&self, channel: usize) {
-        self.channels[channel].play();
+        self.channels[channel].sink.play();
     }
 
     pub fn stop_channel(&self, channel: usize) {
-        self.channels[channel].stop();
+        self.channels[channel].sink.stop();
+    }
+
+    // Moves the point of view the sounds are heard from, the angles are in degrees.  Sounds of the listener entity
+    // itself are heard at full volume in both ears.
+    pub fn set_listener(&mut self, entity: u16, origin: [f32; 3], angles: [f32; 3]) {
+        let [pitch, yaw, roll] = angles.map(f32::to_radians);
+        let (sp, cp) = pitch.sin_cos();
+        let (sy, cy) = yaw.sin_cos();
+        let (sr, cr) = roll.sin_cos();
+
+        self.listener = Listener {
+            entity,
+            origin,
+            right: [
+                (-sr * sp).mul_add(cy, cr * sy),
+                (-sr * sp).mul_add(sy, -cr * cy),
+                -sr * cp,
+            ],
+        };
+    }
+
+    // Stops the sound playing on the entity channel.
+    fn stop_emitter(&self, entity: u16, entity_channel: u8) {
+        for channel in &self.channels {
+            if channel.emitter.is_some_and(|emitter| {
+                emitter.entity == entity && emitter.channel == entity_channel
+            }) {
+                channel.sink.stop();
+            }
+        }
+    }
+
+    // Sends the gains of every playing sound with an emitter, so that they follow the listener.  Static sounds out of
+    // hearing range are paused instead, the way the original engine skips the channels it would mix silent.
+    fn spatialize(&mut self) {
+        for channel in self.channels.iter().filter(|channel| !channel.sink.empty()) {
+            if let Some(emitter) = &channel.emitter {
+                *channel.gains.lock().unwrap() = self.listener.gains(emitter);
+            }
+        }
+
+        for channel in &mut self.static_channels {
+            let Some(emitter) = &channel.emitter else {
+                continue;
+            };
+            if !self.listener.hears(emitter) {
+                if !channel.muted {
+                    channel.sink.pause();
+                    channel.muted = true;
+                }
+                continue;
+            }
+
+            *channel.gains.lock().unwrap() = self.listener.gains(emitter);
+            if channel.muted {
+                channel.muted = false;
+                if !channel.suspended {
+                    channel.sink.play();
+                }
+            }
+        }
+    }
+
+    // A sound replaces the one playing on the same entity channel, otherwise it takes a free effect channel or the
+    // one closest to being over.  Sounds of the listener are never cut by the sounds of other entities.
+    fn pick_channel(&self, entity: u16, entity_channel: u8) -> Option<usize> {
+        let playing = |channel: &Channel| channel.emitter.filter(|_| !channel.sink.empty());
+
+        if entity_channel != 0 {
+            let same_channel = self.channels.iter().position(|channel| {
+                playing(channel).is_some_and(|emitter| {
+                    emitter.entity == entity && emitter.channel == entity_channel
+                })
+            });
+            if same_channel.is_some() {
+                return same_channel;
+            }
+        }
+
+        if let Some(free) = (1..self.channels.len()).find(|&i| self.channels[i].sink.empty()) {
+            return Some(free);
+        }
+
+        (1..self.channels.len())
+            .filter_map(|i| playing(&self.channels[i]).map(|emitter| (i, emitter)))
+            .filter(|(_, emitter)| {
+                emitter.entity != self.listener.entity || entity == self.listener.entity
+            })
+            .min_by_key(|&(i, _)| self.channels[i].ends)
+            .map(|(i, _)| i)
+    }
+
+    fn play_emitter(&mut self, source: SamplesBuffer<i16>, emitter: Emitter) {
+        let gains = self.listener.gains(&emitter);
+        let Some(index) = self.pick_channel(emitter.entity, emitter.channel) else {
+            return;
+        };
+        let now = self.clock.now();
+        self.channels[index].play_emitter(source, emitter, gains, now);
+    }
+
+    fn play_static(&mut self, source: SamplesBuffer<i16>, emitter: Emitter) {
+        // Static sounds take the channels left over by the effects, up to the channel count of the original engine.
+        if self.channels.len() + self.static_channels.len() >= MAX_CHANNELS {
+            tracing::warn!("too many static sounds, skipping one");
+            return;
+        }
+
+        let gains = self.listener.gains(&emitter);
+        match Channel::new(&self.output_stream_handle) {
+            Ok(mut channel) => {
+                channel.sink.set_volume(self.sfx_volume);
+                channel.play_emitter(source.repeat_infinite(), emitter, gains, self.clock.now());
+                self.static_channels.push(channel);
+            }
+            Err(e) => tracing::warn!("couldn't play static sound: {:#}", e),
+        }
+    }
+
+    // Dropping the channels stops their sounds.
+    fn stop_static_sounds(&mut self) {
+        self.static_channels.clear();
+    }
+
+    // Stops the sound effects, along with those played with the play command that are still being decoded.
+    fn stop_sounds(&mut self) {
+        for channel in 1..self.channels.len() {
+            self.stop_channel(channel);
+        }
+        self.stop_static_sounds();
+        self.pending_local_sounds.clear();
+    }
+
+    fn stop_music(&mut self) {
+        self.stop_channel(0);
+        self.pending_music = None;
+    }
+
+    // Starts whatever finished loading since the last call.
+    fn start_pending(&mut self) {
+        let mut pending_local_sounds = std::mem::take(&mut self.pending_local_sounds);
+        pending_local_sounds.retain_mut(|future| match future.try_take() {
+            Some(Ok(pcm)) => {
+                if let Some(channel) =
+                    (1..self.channels.len()).find(|&i| self.channels[i].sink.empty())
+                {
+                    self.play_pcm(channel, &pcm);
+                }
+                false
+            }
+            Some(Err(e)) => {
+                tracing::warn!("couldn't play sound: {:#}", e);
+                false
+            }
+            None => true,
+        });
+        self.pending_local_sounds = pending_local_sounds;
+
+        if let Some((future, looping)) = &mut self.pending_music {
+            let looping = *looping;
+            if let Some(result) = future.try_take() {
+                self.pending_music = None;
+                let result = result.and_then(|buf| {
+                    if looping {
+                        self.loop_channel(0, Cursor::new(buf))
+                    } else {
+                        self.play_channel(0, Cursor::new(buf))
+                    }
+                });
+                if let Err(e) = result {
+                    tracing::warn!("couldn't play music: {:#}", e);
+                }
+            }
+        }
+    }
+
+    // The sounds of the server are looked up in the registry of the current level, which is complete once the level
+    // is loaded since no message is read meanwhile.
+    fn handle_server_message(
+        &mut self,
+        message: &ServerMessage,
+        sound_registry: &mut SoundRegistry,
+        resource_files: &ResourceFiles,
+    ) {
+        match message {
+            // A new level brings its own sounds, the registry is replaced with its own once they are loaded.
+            ServerMessage::ServerInfo { .. } => {
+                self.stop_sounds();
+                *sound_registry = SoundRegistry::default();
+            }
+            ServerMessage::Sound {
+                volume,
+                attenuation,
+                channel,
+                entity_id,
+                sound_id,
+                origin,
+                ..
+            } => {
+                let emitter = Emitter {
+                    entity: *entity_id,
+                    channel: *channel,
+                    origin: *origin,
+                    volume: f32::from(*volume) / 255.0,
+                    attenuation: *attenuation,
+                };
+                if let Some(source) = sound_registry.sound(*sound_id) {
+                    self.play_emitter(source, emitter);
+                }
+            }
+            // Static sounds belong to the world, so they are never heard at full volume as the sounds of the listener.
+            ServerMessage::SpawnStaticSound {
+                origin,
+                soundnum,
+                vol,
+                attenuation,
+            } => {
+                let emitter = Emitter {
+                    entity: 0,
+                    channel: 0,
+                    origin: *origin,
+                    volume: *vol,
+                    attenuation: *attenuation,
+                };
+                if let Some(source) = sound_registry.sound(*soundnum) {
+                    self.play_static(source, emitter);
+                }
+            }
+            // The level music loops until another track is selected.
+            ServerMessage::CdTrack { fromtrack, .. } => {
+                if let Err(e) = self.play_music(*fromtrack, true, resource_files) {
+                    tracing::warn!("couldn't play music: {:#}", e);
+                }
+            }
+            ServerMessage::StopSound { entity_id, channel } => {
+                self.stop_emitter(*entity_id, *channel);
+            }
+            _ => (),
+        }
+    }
+
+    // Reads the track from the music directory, in the first of the supported formats found, and plays it once it
+    // is loaded.  Tracks are numbered as on the original CD, where the first one holds the game data.
+    fn play_music(
+        &mut self,
+        track: u8,
+        looping: bool,
+        resource_files: &ResourceFiles,
+    ) -> anyhow::Result<()> {
+        let Some(file_path) = MUSIC_EXTENSIONS
+            .iter()
+            .map(|extension| format!("music/track{track:02}.{extension}"))
+            .find(|file_path| resource_files.which(file_path).is_some())
+        else {
+            bail!("track {:02} not found", track);
+        };
+
+        self.pending_music = Some((resource_files.load_async(file_path), looping));
+
+        Ok(())
+    }
+
+    pub fn set_music_volume(&self, volume: f32) {
+        self.channels[0].sink.set_volume(volume);
+    }
+
+    pub fn set_sfx_volume(&mut self, volume: f32) {
+        self.sfx_volume = volume;
+        for channel in self.channels[1..].iter().chain(&self.static_channels) {
+            channel.sink.set_volume(volume);
+        }
+    }
+
+    // Pauses everything that is playing, including the sounds started while paused.
+    pub fn pause_all(&mut self) {
+        for channel in self.channels.iter_mut().chain(&mut self.static_channels) {
+            // A static sound muted by the distance is suspended as well, so that it doesn't start again before the
+            // audio is resumed.
+            if !channel.sink.empty() && (!channel.sink.is_paused() || channel.muted) {
+                channel.sink.pause();
+                channel.suspended = true;
+            }
+        }
+    }
+
+    // Resumes what was paused by `pause_all`, the music paused on its own stays paused.
+    pub fn resume_all(&mut self) {
+        for channel in self.channels.iter_mut().chain(&mut self.static_channels) {
+            if channel.suspended {
+                if !channel.muted {
+                    channel.sink.play();
+                }
+                channel.suspended = false;
+            }
+        }
+    }
+
+    fn execute_play_music(
+        &mut self,
+        track_number: &str,
+        looping: bool,
+        resource_files: &ResourceFiles,
+    ) {
+        let Ok(track) = track_number.parse::<u8>() else {
+            tracing::warn!("invalid track number: {}", track_number);
+            return;
+        };
+        if let Err(e) = self.play_music(track, looping, resource_files) {
+            tracing::warn!("couldn't play music: {:#}", e);
+        }
     }
 
     fn execute_command(&mut self, command: &ConsoleCmd, resource_files: &mut ResourceFiles) {
         match &command[..] {
             // Plays the specified track one time.
             [ref cmd, ref action, track_number] if cmd == "cd" && action == "play" => {
-                let audio_path = PathBuf::from_str(
-                    format!(
-                        "music/track{:02}.ogg",
-                        track_number.parse::<i32>().unwrap_or(0)
-                    )
-                    .as_str(),
-                )
-                .unwrap();
-                let data = resource_files.take(audio_path).unwrap();
-                self.play_channel(0, data).unwrap();
+                self.execute_play_music(track_number, false, resource_files);
             }
             // Plays the specified track.  It will be repeated until either it is manually stopped or another track is started.
             [ref cmd, ref action, track_number] if cmd == "cd" && action == "loop" => {
-                let audio_path = PathBuf::from_str(
-                    format!(
-                        "music/track{:02}.ogg",
-                        track_number.parse::<i32>().unwrap_or(0)
-                    )
-                    .as_str(),
-                )
-                .unwrap();
-                let data = resource_files.take(audio_path).unwrap();
-                self.loop_channel(0, data).unwrap();
+                self.execute_play_music(track_number, true, resource_files);
             }
             // Stops the currently playing track.
             [ref cmd, ref action] if cmd == "cd" && action == "stop" => {
+                self.pending_music = None;
                 self.pause_channel(0);
             }
             // Will resume playback of a stopped track.
             [ref cmd, ref action] if cmd == "cd" && action == "resume" => {
                 self.resume_channel(0);
             }
+            // Loops the specified track.
+            [ref cmd, track_number] if cmd == "music" => {
+                self.execute_play_music(track_number, true, resource_files);
+            }
+            // Pauses the music, it goes on from the same point when resumed.
+            [ref cmd] if cmd == "music_pause" => {
+                self.pause_channel(0);
+            }
+            [ref cmd] if cmd == "music_resume" => {
+                self.resume_channel(0);
+            }
+            [ref cmd] if cmd == "music_stop" => {
+                self.stop_music();
+            }
             // Play a sound effect.
             [ref cmd, file_path] if cmd == "play" => {
-                for channel in 1..self.channels.len() {
-                    if self.channels[channel].empty() {
-                        let data = resource_files.take(file_path).unwrap();
-                        self.play_channel(channel, data).unwrap();
-                        break;
-                    }
-                }
+                let pcm_cache = self.pcm_cache.clone();
+                let future =
+                    resource_files.load_async_with(file_path, move |data| pcm_cache.load(data));
+                self.pending_local_sounds.push(future);
             }
             // Stops all sounds currently being played.
             [ref cmd] if cmd == "stopsound" => {
-                for channel in 1..self.channels.len() {
-                    self.stop_channel(channel);
+                self.stop_sounds();
+            }
+            // Removes the decoded sounds cached on disk.
+            [ref cmd] if cmd == "snd_cachepurge" => {
+                if let Err(e) = self.pcm_cache.purge() {
+                    tracing::warn!("couldn't purge the sound cache: {:#}", e);
                 }
             }
             _ => (),
@@ -134,3 +910,53 @@ pub fn audio_command_executor(
         .commands()
         .for_each(|command| audio.execute_command(command, resource_files));
 }
+
+// Plays the sounds and the music started by the server this frame and moves the listener to the view of the client.
+#[system]
+pub fn audio_update(
+    #[resource] audio: &mut Audio,
+    #[resource] connection: &Connection,
+    #[resource] sound_registry: &mut SoundRegistry,
+    #[resource] console: &Console,
+    #[resource] resource_files: &ResourceFiles,
+    #[resource] world_time: &WorldTime,
+    #[resource] window: &Arc<Window>,
+) {
+    if connection
+        .events()
+        .any(|state| *state == ConnectionState::Disconnected)
+    {
+        audio.stop_sounds();
+        audio.stop_music();
+    }
+    if console.changed_vars().any(|name| name == "volume") {
+        let volume = console
+            .get_var::<f32>("volume")
+            .unwrap_or(DEFAULT_SFX_VOLUME);
+        audio.set_sfx_volume(volume.clamp(0.0, 1.0));
+    }
+    if console
+        .changed_vars()
+        .any(|name| name == "bgmvolume" || name == "music_volume")
+    {
+        let bgmvolume = console.get_var::<f32>("bgmvolume").unwrap_or(1.0);
+        let music_volume = console.get_var::<f32>("music_volume").unwrap_or(1.0);
+        audio.set_music_volume(bgmvolume.clamp(0.0, 1.0) * music_volume.clamp(0.0, 1.0));
+    }
+    // Nothing is heard while the game is paused or the window is in the background.
+    if world_time.is_paused() || !window.has_focus() {
+        audio.pause_all();
+    } else {
+        audio.resume_all();
+    }
+    connection
+        .messages()
+        .for_each(|message| audio.handle_server_message(message, sound_registry, resource_files));
+    audio.start_pending();
+    audio.set_listener(
+        connection.view_entity(),
+        connection.view_origin(),
+        connection.view_angles(),
+    );
+    audio.spatialize();
+}
@@ -0,0 +1,224 @@
+use legion::system;
+
+use crate::{
+    console::Console,
+    message::{ServerMessage, U_ORIGIN1, U_ORIGIN2, U_ORIGIN3},
+};
+
+// Steps the server takes the client through before it is part of the game, each one is announced with a signon
+// message carrying its number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignonStage {
+    // The server info arrived and the level resources are being precached.
+    ServerInfo = 0,
+    // The entity baselines and static entities are being received.
+    Prespawn = 1,
+    // The client sent its name and colors, the server is spawning it.
+    ClientInfo = 2,
+    // The client is spawned and waits for the first entity update.
+    Begin = 3,
+}
+
+impl SignonStage {
+    const fn next(self) -> Option<Self> {
+        match self {
+            Self::ServerInfo => Some(Self::Prespawn),
+            Self::Prespawn => Some(Self::ClientInfo),
+            Self::ClientInfo => Some(Self::Begin),
+            Self::Begin => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConnectionState {
+    #[default]
+    Disconnected,
+    // A demo or a server was opened but no server info arrived yet.
+    Connecting,
+    Signon(SignonStage),
+    // The first entity update after the last signon stage arrived, the game is running.
+    Active,
+}
+
+impl ConnectionState {
+    // Name of the event console hooks use for entering the state.
+    #[must_use]
+    pub const fn event_name(self) -> Option<&'static str> {
+        match self {
+            Self::Disconnected => Some("disconnect"),
+            Self::Connecting => Some("connect"),
+            Self::Signon(SignonStage::ServerInfo) => Some("mapload"),
+            Self::Signon(_) => None,
+            Self::Active => Some("mapstart"),
+        }
+    }
+}
+
+// Connection lifecycle of the client, driven by the messages coming from the server.
+#[derive(Default)]
+pub struct Connection {
+    state: ConnectionState,
+    // Name of the level announced by the server info, such as "the Slipgate Complex".
+    level_name: Option<String>,
+    // States entered during the current frame, in order.
+    events: Vec<ConnectionState>,
+    // Server messages received during the current frame, in order.
+    messages: Vec<ServerMessage>,
+    // Entity the client sees the world from, with the origin of its baseline and the current one.
+    view_entity: u16,
+    view_baseline: [f32; 3],
+    view_origin: [f32; 3],
+    // Direction the client looks at, in degrees.
+    view_angles: [f32; 3],
+}
+
+impl Connection {
+    #[must_use]
+    pub const fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    #[must_use]
+    pub fn level_name(&self) -> Option<&str> {
+        self.level_name.as_deref()
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &ConnectionState> {
+        self.events.iter()
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &ServerMessage> {
+        self.messages.iter()
+    }
+
+    #[must_use]
+    pub const fn view_entity(&self) -> u16 {
+        self.view_entity
+    }
+
+    #[must_use]
+    pub const fn view_origin(&self) -> [f32; 3] {
+        self.view_origin
+    }
+
+    #[must_use]
+    pub const fn view_angles(&self) -> [f32; 3] {
+        self.view_angles
+    }
+
+    // Demo blocks carry the view angles of the client that recorded them.
+    pub const fn set_view_angles(&mut self, angles: [f32; 3]) {
+        self.view_angles = angles;
+    }
+
+    // Forgets the states and the messages of the previous frame.
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+        self.messages.clear();
+    }
+
+    pub fn connect(&mut self) {
+        self.set_state(ConnectionState::Connecting);
+    }
+
+    pub fn disconnect(&mut self) {
+        self.level_name = None;
+        self.set_state(ConnectionState::Disconnected);
+    }
+
+    // Messages that do not fit the current state, such as a signon stage that is skipped or repeated, are reported
+    // and ignored instead of moving the connection to a state it cannot be in.
+    pub fn handle_server_message(&mut self, message: &ServerMessage) {
+        self.messages.push(message.clone());
+        self.update_view(message);
+
+        match (self.state, message) {
+            (_, ServerMessage::Disconnect) => self.disconnect(),
+            (ConnectionState::Disconnected, _) => (),
+            (_, ServerMessage::ServerInfo { mapname, .. }) => {
+                self.level_name = Some(mapname.clone());
+                self.set_state(ConnectionState::Signon(SignonStage::ServerInfo));
+            }
+            (ConnectionState::Signon(stage), ServerMessage::SignOnStage { signon }) => {
+                match stage.next() {
+                    Some(next) if next as u8 == *signon => {
+                        self.set_state(ConnectionState::Signon(next));
+                    }
+                    _ => tracing::warn!("unexpected signon {} at {:?}", signon, stage),
+                }
+            }
+            (_, ServerMessage::SignOnStage { signon }) => {
+                tracing::warn!("unexpected signon {} while {:?}", signon, self.state);
+            }
+            (ConnectionState::Signon(SignonStage::Begin), ServerMessage::Updateentity { .. }) => {
+                self.set_state(ConnectionState::Active);
+            }
+            _ => (),
+        }
+    }
+
+    // The origin fields of an entity update are only sent when they differ from the baseline.
+    fn update_view(&mut self, message: &ServerMessage) {
+        match message {
+            ServerMessage::SetView { entity } => self.view_entity = *entity,
+            ServerMessage::SetAngle { angles } => self.view_angles = *angles,
+            ServerMessage::SpawnBaseline {
+                entity,
+                default_origin,
+                ..
+            } if *entity == self.view_entity => {
+                self.view_baseline = *default_origin;
+                self.view_origin = *default_origin;
+            }
+            ServerMessage::Updateentity {
+                mask,
+                entity,
+                origin,
+                ..
+            } if *entity == self.view_entity => {
+                for (i, bit) in [U_ORIGIN1, U_ORIGIN2, U_ORIGIN3].into_iter().enumerate() {
+                    self.view_origin[i] = if mask & bit != 0 {
+                        origin[i]
+                    } else {
+                        self.view_baseline[i]
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        if self.state != state {
+            self.state = state;
+            self.events.push(state);
+        }
+    }
+}
+
+#[system]
+pub fn connection_event_dispatcher(
+    #[resource] connection: &Connection,
+    #[resource] console: &mut Console,
+) {
+    // Runs the console hooks of the connection states entered this frame.
+    connection
+        .events()
+        .filter_map(|state| state.event_name())
+        .for_each(|event| console.fire_event(event));
+}
+
+#[system]
+pub fn connection_command_executor(#[resource] console: &mut Console) {
+    // Chat messages need a server to go to, demos can't take them.
+    let refused: Vec<String> = console
+        .commands()
+        .filter(|command| command[0] == "say" || command[0] == "say_team")
+        .map(|command| format!("can't {}, not connected to a server", command[0]))
+        .collect();
+
+    for line in &refused {
+        console.print_warning(line);
+    }
+}
@@ -1,74 +1,318 @@
 use std::{
-    any::Any,
     collections::{vec_deque::Iter, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+    str::FromStr,
 };
 
+use anyhow::bail;
+use bitflags::bitflags;
 use legion::system;
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_while1},
-    character::complete::{line_ending, not_line_ending, space0},
-    combinator::{opt, recognize},
-    multi::{many0, many1},
-    sequence::{delimited, preceded, terminated, tuple},
+    character::complete::{char, line_ending, not_line_ending, one_of, space0},
+    combinator::{all_consuming, map, map_opt, map_res, not, opt, recognize},
+    multi::{fold_many0, many0, many1},
+    number::complete::recognize_float,
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
-use crate::ResourceFiles;
+use crate::{
+    input::{Input, InputFocus},
+    ResourceFiles,
+};
 
 pub type ConsoleCmd = Vec<String>;
-pub type ConsoleVar = Box<dyn Any + Send + Sync>;
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct CvarFlags: u32 {
+        // Saved to config.cfg.
+        const ARCHIVE = 1 << 0;
+        // Part of the server info sent to clients.
+        const SERVERINFO = 1 << 1;
+        // Only changeable from the console while sv_cheats is enabled.
+        const CHEAT = 1 << 2;
+        // Not changeable from the console at all.
+        const ROM = 1 << 3;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Cvar {
+    value: String,
+    default: String,
+    description: String,
+    flags: CvarFlags,
+}
+
+impl Cvar {
+    #[must_use]
+    pub fn new(default: &str, description: &str, flags: CvarFlags) -> Self {
+        Self {
+            value: default.to_owned(),
+            default: default.to_owned(),
+            description: description.to_owned(),
+            flags,
+        }
+    }
+
+    #[must_use]
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    #[must_use]
+    pub fn default(&self) -> &str {
+        &self.default
+    }
+
+    #[must_use]
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    #[must_use]
+    pub const fn flags(&self) -> CvarFlags {
+        self.flags
+    }
+}
+
+// Variables can reference other variables, this is how deep those references are followed.
+const MAX_EXPANSION_DEPTH: usize = 8;
+// Longest arithmetic expression accepted by `set`.
+const MAX_EXPRESSION_LENGTH: usize = 256;
+// Aliases and scripts can run other aliases and scripts, this is how deep they can be nested.
+const MAX_EXECUTION_DEPTH: usize = 16;
+// Commands the console runs in a single frame before giving up on the rest.
+const MAX_COMMANDS_PER_FRAME: usize = 4096;
+
+// Problems found while running commands, reported for the frame they happened in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConsoleError {
+    // An alias or a script kept calling itself, or other aliases or scripts, too deep.
+    RecursionTooDeep { command: String },
+    // Too many commands were run in a single frame.
+    BudgetExceeded { command: String },
+}
+
+// Lines kept by the console output, older ones are dropped first.
+const OUTPUT_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputLevel {
+    Info,
+    Warning,
+    Error,
+}
+
+// Text printed to the console, kept for the console overlay and mirrored to the log.
+#[derive(Default)]
+pub struct ConsoleOutput {
+    lines: VecDeque<(OutputLevel, String)>,
+}
+
+impl ConsoleOutput {
+    pub fn push(&mut self, level: OutputLevel, text: &str) {
+        for line in text.lines() {
+            match level {
+                OutputLevel::Info => tracing::info!("{}", line),
+                OutputLevel::Warning => tracing::warn!("{}", line),
+                OutputLevel::Error => tracing::error!("{}", line),
+            }
+
+            if self.lines.len() == OUTPUT_CAPACITY {
+                self.lines.pop_front();
+            }
+            self.lines.push_back((level, line.to_owned()));
+        }
+    }
+
+    // Lines from the oldest to the most recent.
+    #[must_use]
+    pub fn lines(&self) -> impl DoubleEndedIterator<Item = (OutputLevel, &str)> {
+        self.lines
+            .iter()
+            .map(|(level, line)| (*level, line.as_str()))
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+}
 
 #[derive(Default)]
 pub struct Console {
     command_registry: HashSet<String>,
     command_queue: VecDeque<ConsoleCmd>,
-    variables: HashMap<String, ConsoleVar>,
-    alias: HashMap<String, ConsoleCmd>,
+    variables: HashMap<String, Cvar>,
+    changed_variables: Vec<String>,
+    alias: HashMap<String, Vec<ConsoleCmd>>,
+    // Commands run when an event happens, by event name.
+    hooks: HashMap<String, Vec<ConsoleCmd>>,
+    executed_commands: usize,
+    errors: Vec<ConsoleError>,
+    output: ConsoleOutput,
 }
 
 impl Console {
+    // Queues every command of the line, commands are separated by semicolons or line breaks.
     pub fn push_command(&mut self, cmd: &str) {
-        let cmd = format!("{}\r\n", cmd.trim().to_lowercase());
-        let (_remaining, command) = Self::command(cmd.as_str()).unwrap();
-        self.command_queue
-            .push_back(command.iter().map(|s| s.to_string()).collect());
+        let commands = Self::tokenize(cmd);
+        self.command_queue.extend(commands);
     }
 
     pub fn commands(&self) -> Iter<'_, Vec<String>> {
         self.command_queue.iter()
     }
 
-    pub fn set_var<T: Send + Sync + 'static>(&mut self, var_name: &str, var_value: T) {
-        self.variables
-            .insert(var_name.to_string(), Box::new(var_value));
+    // Registers a variable with its metadata.  A value assigned before the registration, for example by a script, is
+    // kept unless the variable is read only.
+    pub fn register_variable(&mut self, var_name: &str, mut cvar: Cvar) {
+        let var_name = var_name.trim().to_lowercase();
+        if let Some(previous) = self.variables.get(&var_name) {
+            if !cvar.flags.contains(CvarFlags::ROM) {
+                cvar.value.clone_from(&previous.value);
+            }
+        }
+        self.variables.insert(var_name, cvar);
+    }
+
+    // Sets the value of a variable regardless of its flags, variables that are not registered yet are created.
+    pub fn set_var(&mut self, var_name: &str, var_value: &str) {
+        let cvar = self
+            .variables
+            .entry(var_name.to_owned())
+            .or_insert_with(|| Cvar::new("", "", CvarFlags::empty()));
+        if cvar.value != var_value {
+            var_value.clone_into(&mut cvar.value);
+            self.changed_variables.push(var_name.to_owned());
+        }
     }
 
-    pub fn get_var<T: Send + Sync + 'static>(&self, var_name: &str) -> Option<&T> {
-        self.variables.get(var_name)?.downcast_ref::<T>()
+    #[must_use]
+    pub fn get_var<T: FromStr>(&self, var_name: &str) -> Option<T> {
+        self.variables.get(var_name)?.value.parse().ok()
+    }
+
+    #[must_use]
+    pub fn get_cvar(&self, var_name: &str) -> Option<&Cvar> {
+        self.variables.get(var_name)
+    }
+
+    pub fn reset_var(&mut self, var_name: &str) {
+        if let Some(default) = self
+            .variables
+            .get(var_name)
+            .map(|cvar| cvar.default.clone())
+        {
+            self.set_var(var_name, &default);
+        }
     }
 
     pub fn remove_var(&mut self, var_name: &str) {
         self.variables.remove(var_name);
     }
 
-    pub fn set_alias(&mut self, alias: &str, cmd: String) {
-        let cmd = format!("{}\r\n", cmd.trim().to_lowercase());
-        let (_remaining, command) = Self::command(cmd.as_str()).unwrap();
-        self.alias.insert(
-            alias.to_string(),
-            command.iter().map(|s| s.to_string()).collect(),
-        );
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Cvar)> {
+        self.variables
+            .iter()
+            .map(|(name, cvar)| (name.as_str(), cvar))
+    }
+
+    // Variables changed during the current frame, so that subsystems can react to them like they do to commands.
+    pub fn changed_vars(&self) -> impl Iterator<Item = &str> {
+        self.changed_variables.iter().map(String::as_str)
+    }
+
+    pub fn print(&mut self, text: &str) {
+        self.output.push(OutputLevel::Info, text);
+    }
+
+    pub fn print_warning(&mut self, text: &str) {
+        self.output.push(OutputLevel::Warning, text);
+    }
+
+    pub fn print_error(&mut self, text: &str) {
+        self.output.push(OutputLevel::Error, text);
+    }
+
+    #[must_use]
+    pub const fn output(&self) -> &ConsoleOutput {
+        &self.output
+    }
+
+    // Errors raised while running the commands of the current frame.
+    pub fn errors(&self) -> impl Iterator<Item = &ConsoleError> {
+        self.errors.iter()
+    }
+
+    pub fn set_alias(&mut self, alias: &str, cmd: &str) {
+        let commands = Self::tokenize(cmd);
+        self.alias.insert(alias.to_string(), commands);
     }
 
-    pub fn get_alias(&mut self, alias: &str) -> Option<&ConsoleCmd> {
-        self.alias.get(alias)
+    #[must_use]
+    pub fn get_alias(&self, alias: &str) -> Option<&[ConsoleCmd]> {
+        self.alias.get(alias).map(Vec::as_slice)
     }
 
     pub fn remove_alias(&mut self, alias: &str) {
         self.alias.remove(alias);
     }
 
+    pub fn set_hook(&mut self, event: &str, cmd: &str) {
+        let commands = Self::tokenize(cmd);
+        self.hooks.insert(event.to_lowercase(), commands);
+    }
+
+    pub fn remove_hook(&mut self, event: &str) {
+        self.hooks.remove(event.to_lowercase().as_str());
+    }
+
+    // Queues the commands hooked to the event, they run with the rest of the commands of the frame.
+    pub fn fire_event(&mut self, event: &str) {
+        if let Some(commands) = self.hooks.get(event) {
+            self.command_queue.extend(commands.iter().cloned());
+        }
+    }
+
+    // Writes the console variables and the key bindings as a script that restores them when executed.
+    pub fn write_config<W: Write>(&self, writer: &mut W, input: &Input) -> anyhow::Result<()> {
+        writeln!(writer, "// generated by quake-rs, do not modify")?;
+
+        let mut bindings: Vec<_> = input.bindings().collect();
+        bindings.sort_unstable();
+        for (key, action) in bindings {
+            writeln!(writer, "bind \"{key}\" \"{action}\"")?;
+        }
+
+        let mut variables: Vec<_> = self
+            .variables
+            .iter()
+            .filter(|(_, cvar)| cvar.flags.contains(CvarFlags::ARCHIVE))
+            .map(|(name, cvar)| (name, &cvar.value))
+            .collect();
+        variables.sort_unstable();
+        for (name, value) in variables {
+            writeln!(writer, "{name} \"{value}\"")?;
+        }
+
+        Ok(())
+    }
+
     pub fn register_command(&mut self, cmd_name: &str) {
         let cmd_name = cmd_name.trim().to_lowercase();
         self.command_registry.insert(cmd_name.to_owned());
@@ -79,34 +323,91 @@ impl Console {
         self.command_registry.remove(cmd_name.as_str());
     }
 
+    // Returns the sorted names of the commands, aliases and variables that start with the given prefix.
+    #[must_use]
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        let prefix = prefix.trim().to_lowercase();
+        let mut names: Vec<String> = self
+            .command_registry
+            .iter()
+            .chain(self.alias.keys())
+            .chain(self.variables.keys())
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+
+        names
+    }
+
+    // Names matching an optional wildcard pattern such as "sv_*", every name matches when there is no pattern.
+    fn filter_names<'a, I>(names: I, pattern: Option<&str>) -> Vec<&'a str>
+    where
+        I: Iterator<Item = &'a String>,
+    {
+        let pattern = match pattern.map(glob::Pattern::new).transpose() {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                tracing::warn!("invalid pattern: {:#}", e);
+                return Vec::new();
+            }
+        };
+
+        let mut names: Vec<&str> = names
+            .filter(|name| pattern.as_ref().is_none_or(|p| p.matches(name)))
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+
+        names
+    }
+
+    // Counts the command against the limits of the frame, reporting the first time each limit is hit.
+    fn within_budget(&mut self, command: &ConsoleCmd, depth: usize) -> bool {
+        let name = command.first().cloned().unwrap_or_default();
+        if depth > MAX_EXECUTION_DEPTH {
+            // A command calling itself several times hits the limit on every branch, it is reported once.
+            let error = ConsoleError::RecursionTooDeep {
+                command: name.clone(),
+            };
+            if !self.errors.contains(&error) {
+                self.print_warning(&format!("{name} nested too deep, ignored"));
+                self.errors.push(error);
+            }
+            return false;
+        }
+        self.executed_commands += 1;
+        if self.executed_commands > MAX_COMMANDS_PER_FRAME {
+            // Reported once, everything after it is dropped silently.
+            if self.executed_commands == MAX_COMMANDS_PER_FRAME + 1 {
+                self.print_warning(&format!(
+                    "too many commands in a frame, {name} and the rest are ignored"
+                ));
+                self.errors
+                    .push(ConsoleError::BudgetExceeded { command: name });
+            }
+            return false;
+        }
+
+        true
+    }
+
     fn execute_command(
         &mut self,
         command: &ConsoleCmd,
         resource_files: &mut ResourceFiles,
+        depth: usize,
     ) -> Option<VecDeque<ConsoleCmd>> {
+        if !self.within_budget(command, depth) {
+            return None;
+        }
+
+        let command = &self.expand_command(command);
         match &command[..] {
             // Execute a script file.
             [ref cmd, filename] if cmd == "exec" => {
-                let mut buf = String::new();
-                let mut queue = VecDeque::new();
-
-                if let Ok(mut reader) = resource_files.take(filename) {
-                    reader.read_to_string(&mut buf).unwrap();
-                    buf.push_str("\r\n");
-                }
-
-                if let Ok((_remaining, commands)) = Self::many_commands(buf.as_str()) {
-                    for cmd in commands {
-                        let cmd: Vec<String> =
-                            cmd.iter().map(|s| s.trim().to_lowercase()).collect();
-
-                        if let Some(mut result) = self.execute_command(&cmd, resource_files) {
-                            queue.append(&mut result);
-                        }
-                    }
-                }
-
-                Some(queue)
+                self.execute_script(filename, resource_files, depth)
             }
             // The alias command is used to create a reference to a command or list of commands.  When aliasing multiple commands,
             // or commands that contain multiple words (such as "fraglimit 50"), you must enclose all the commands in quotation
@@ -117,31 +418,350 @@ impl Console {
                 if command.is_empty() {
                     self.remove_alias(name);
                 } else {
-                    self.set_alias(name, command[0].to_owned());
+                    self.set_alias(name, &command.join(" "));
+                }
+
+                None
+            }
+            // Runs commands whenever an event happens, such as "on mapstart "exec dmsettings.cfg"".  Without commands
+            // the hook of the event is removed.
+            [ref cmd, event, command @ ..] if cmd == "on" => {
+                if command.is_empty() {
+                    self.remove_hook(event);
+                } else {
+                    self.set_hook(event, &command.join(" "));
                 }
 
                 None
             }
-            [ref cvar, value] if !self.command_registry.contains(cvar) => {
-                self.set_var(cvar, value.to_owned());
+            // Prints the arguments.
+            [ref cmd, text @ ..] if cmd == "echo" => {
+                self.print(&text.join(" "));
+
+                None
+            }
+            // Clears the console output.
+            [ref cmd] if cmd == "clear" => {
+                self.output.clear();
+
+                None
+            }
+            // Lists the registered commands, optionally only those matching a wildcard pattern.
+            [ref cmd, pattern @ ..] if cmd == "cmdlist" && pattern.len() <= 1 => {
+                self.list_commands(pattern.first().map(String::as_str));
+
+                None
+            }
+            // Lists the console variables with their values, optionally only those matching a wildcard pattern.
+            [ref cmd, pattern @ ..] if cmd == "cvarlist" && pattern.len() <= 1 => {
+                self.list_variables(pattern.first().map(String::as_str));
+
+                None
+            }
+            // Sets the value of a console variable, arithmetic expressions such as "$(fov + 5)" are evaluated first.
+            [ref cmd, cvar, value] if cmd == "set" => {
+                match self.evaluate(value) {
+                    Ok(value) => self.assign_var(cvar, &value),
+                    Err(e) => self.print_warning(&format!("{e:#}")),
+                }
+
+                None
+            }
+            // Switches a variable between 0 and 1, any value other than 0 counts as 1.
+            [ref cmd, cvar] if cmd == "toggle" => {
+                self.toggle_var(cvar);
+
+                None
+            }
+            // Adds a delta to a numeric variable, 1 by default.  With a range the value wraps around its ends.
+            [ref cmd, cvar, args @ ..] if cmd == "inc" && args.len() <= 3 && args.len() != 2 => {
+                let args: Option<Vec<f32>> = args.iter().map(|arg| arg.parse().ok()).collect();
+                match args.as_deref() {
+                    Some([]) => self.inc_var(cvar, 1.0, None),
+                    Some([delta]) => self.inc_var(cvar, *delta, None),
+                    Some([delta, min, max]) => self.inc_var(cvar, *delta, Some((*min, *max))),
+                    _ => self.print_warning("usage: inc <cvar> [delta] [min max]"),
+                }
+
+                None
+            }
+            // Sets a variable to the value following its current one in the list, going back to the first one.
+            [ref cmd, cvar, values @ ..] if cmd == "cycle" && !values.is_empty() => {
+                self.cycle_var(cvar, values);
+
+                None
+            }
+            [ref cvar, value]
+                if !self.command_registry.contains(cvar) && !self.alias.contains_key(cvar) =>
+            {
+                match self.evaluate(value) {
+                    Ok(value) => self.assign_var(cvar, &value),
+                    Err(e) => self.print_warning(&format!("{e:#}")),
+                }
 
                 None
             }
+            // The commands of an alias run in place of it, the arguments given to it are appended to its last command.
             [alias, args @ ..] if self.alias.contains_key(alias) => {
-                if let Some(cmd) = self.get_alias(alias) {
-                    Some(VecDeque::from([cmd
-                        .iter()
-                        .chain(args.iter())
-                        .cloned()
-                        .collect()]))
-                } else {
-                    None
+                let mut commands = self.alias[alias].clone();
+                if let Some(last) = commands.last_mut() {
+                    last.extend(args.iter().cloned());
+                }
+
+                let mut queue = VecDeque::new();
+                for cmd in commands {
+                    if let Some(mut result) = self.execute_command(&cmd, resource_files, depth + 1)
+                    {
+                        queue.append(&mut result);
+                    }
                 }
+
+                Some(queue)
             }
             _ => Some(VecDeque::from([command.to_owned()])),
         }
     }
 
+    // Assignments coming from the console honour the variable flags, unlike `set_var`.
+    fn assign_var(&mut self, var_name: &str, var_value: &str) {
+        let flags = self
+            .variables
+            .get(var_name)
+            .map_or(CvarFlags::empty(), |cvar| cvar.flags);
+        if flags.contains(CvarFlags::ROM) {
+            self.print_warning(&format!("{var_name} is read only"));
+            return;
+        }
+        if flags.contains(CvarFlags::CHEAT) && !self.cheats_enabled() {
+            self.print_warning(&format!("{var_name} is cheat protected"));
+            return;
+        }
+
+        self.set_var(var_name, var_value);
+
+        // Turning cheats off puts every cheat protected variable back to its default.
+        if var_name == "sv_cheats" && !self.cheats_enabled() {
+            let cheat_variables: Vec<_> = self
+                .variables
+                .iter()
+                .filter(|(_, cvar)| cvar.flags.contains(CvarFlags::CHEAT))
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in cheat_variables {
+                self.reset_var(&name);
+            }
+        }
+    }
+
+    fn execute_script(
+        &mut self,
+        filename: &str,
+        resource_files: &mut ResourceFiles,
+        depth: usize,
+    ) -> Option<VecDeque<ConsoleCmd>> {
+        let mut buf = String::new();
+        let mut queue = VecDeque::new();
+
+        if let Err(e) = resource_files
+            .take(filename)
+            .and_then(|mut reader| Ok(reader.read_to_string(&mut buf)?))
+        {
+            self.print_error(&format!("couldn't exec {filename}: {e:#}"));
+            return None;
+        }
+
+        for cmd in Self::tokenize(&buf) {
+            if let Some(mut result) = self.execute_command(&cmd, resource_files, depth + 1) {
+                queue.append(&mut result);
+            }
+        }
+
+        Some(queue)
+    }
+
+    fn list_commands(&mut self, pattern: Option<&str>) {
+        let names: Vec<String> = Self::filter_names(self.command_registry.iter(), pattern)
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        for name in &names {
+            self.print(name);
+        }
+        self.print(&format!("{} commands", names.len()));
+    }
+
+    fn list_variables(&mut self, pattern: Option<&str>) {
+        let lines: Vec<String> = Self::filter_names(self.variables.keys(), pattern)
+            .into_iter()
+            .map(|name| {
+                let cvar = &self.variables[name];
+                format!("{} \"{}\" {}", name, cvar.value, cvar.description)
+            })
+            .collect();
+        for line in &lines {
+            self.print(line);
+        }
+        self.print(&format!("{} variables", lines.len()));
+    }
+
+    fn toggle_var(&mut self, var_name: &str) {
+        let value = self.get_var::<f32>(var_name).unwrap_or(0.0);
+        self.assign_var(var_name, if value == 0.0 { "1" } else { "0" });
+    }
+
+    fn cycle_var(&mut self, var_name: &str, values: &[String]) {
+        let current = self.get_cvar(var_name).map(|cvar| cvar.value.clone());
+        let next = current
+            .and_then(|current| values.iter().position(|value| *value == current))
+            .map_or(0, |position| (position + 1) % values.len());
+        self.assign_var(var_name, &values[next]);
+    }
+
+    fn inc_var(&mut self, var_name: &str, delta: f32, range: Option<(f32, f32)>) {
+        let mut value = self.get_var::<f32>(var_name).unwrap_or(0.0) + delta;
+        if let Some((min, max)) = range {
+            if value > max {
+                value = min;
+            } else if value < min {
+                value = max;
+            }
+        }
+
+        self.assign_var(var_name, &value.to_string());
+    }
+
+    fn cheats_enabled(&self) -> bool {
+        self.get_var::<i32>("sv_cheats")
+            .is_some_and(|cheats| cheats != 0)
+    }
+
+    // Replaces the $name references in the arguments with the value of the variables.  The commands that store other
+    // commands keep them as they are, so that their references are expanded when those commands run.
+    fn expand_command(&self, command: &ConsoleCmd) -> ConsoleCmd {
+        match command.first() {
+            Some(cmd) if cmd == "alias" || cmd == "bind" || cmd == "on" => command.clone(),
+            _ => command.iter().map(|arg| self.expand(arg, 0)).collect(),
+        }
+    }
+
+    // "$$" stands for a literal dollar sign and "$(" starts an expression, which is left for `evaluate`.  References to
+    // unknown variables are kept as they are.  Values referencing other variables are expanded too, up to a depth
+    // that stops variables referencing themselves.
+    fn expand(&self, text: &str, depth: usize) -> String {
+        if depth > MAX_EXPANSION_DEPTH {
+            tracing::warn!("variable expansion too deep: {}", text);
+            return text.to_owned();
+        }
+
+        let mut expanded = String::with_capacity(text.len());
+        let mut remaining = text;
+        while let Some(position) = remaining.find('$') {
+            expanded.push_str(&remaining[..position]);
+            remaining = &remaining[position + 1..];
+
+            if let Some(rest) = remaining.strip_prefix('$') {
+                expanded.push('$');
+                remaining = rest;
+                continue;
+            }
+
+            let name_length = remaining
+                .find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or(remaining.len());
+            let name = &remaining[..name_length];
+            match self.get_cvar(name) {
+                Some(cvar) if !name.is_empty() => {
+                    expanded.push_str(&self.expand(&cvar.value, depth + 1));
+                }
+                _ => {
+                    expanded.push('$');
+                    expanded.push_str(name);
+                }
+            }
+            remaining = &remaining[name_length..];
+        }
+        expanded.push_str(remaining);
+
+        expanded
+    }
+
+    pub(crate) fn evaluate(&self, value: &str) -> anyhow::Result<String> {
+        let Some(expression) = value.strip_prefix("$(").and_then(|v| v.strip_suffix(')')) else {
+            return Ok(value.to_owned());
+        };
+        // The parser recurses on every parenthesis and sign, long expressions could run it out of stack.
+        if expression.len() > MAX_EXPRESSION_LENGTH {
+            bail!("expression too long: {}", expression);
+        }
+
+        let Ok((_remaining, result)) = all_consuming(|i| self.expression(i))(expression) else {
+            bail!("invalid expression: {}", expression);
+        };
+        // A division by zero gives an infinity or a NaN, which no variable can make use of.
+        if !result.is_finite() {
+            bail!("expression has no finite value: {}", expression);
+        }
+
+        Ok(result.to_string())
+    }
+
+    fn expression<'a>(&self, input: &'a str) -> IResult<&'a str, f32> {
+        let (input, first) = self.term(input)?;
+        fold_many0(
+            pair(delimited(space0, one_of("+-"), space0), |i| self.term(i)),
+            move || first,
+            |acc, (op, value)| if op == '+' { acc + value } else { acc - value },
+        )(input)
+    }
+
+    fn term<'a>(&self, input: &'a str) -> IResult<&'a str, f32> {
+        let (input, first) = self.factor(input)?;
+        fold_many0(
+            pair(delimited(space0, one_of("*/"), space0), |i| self.factor(i)),
+            move || first,
+            |acc, (op, value)| if op == '*' { acc * value } else { acc / value },
+        )(input)
+    }
+
+    // Numeric literals, console variables holding a number, negations and parenthesized sub-expressions.
+    fn factor<'a>(&self, input: &'a str) -> IResult<&'a str, f32> {
+        delimited(
+            space0,
+            alt((
+                map_res(recognize_float, str::parse),
+                delimited(char('('), |i| self.expression(i), char(')')),
+                map(preceded(char('-'), |i| self.factor(i)), |v: f32| -v),
+                map_opt(Self::variable_name, |name| self.get_var::<f32>(name)),
+            )),
+            space0,
+        )(input)
+    }
+
+    fn variable_name(input: &str) -> IResult<&str, &str> {
+        take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
+    }
+
+    // Splits a script into commands and arguments, anything after the first malformed command is dropped.
+    pub(crate) fn tokenize(text: &str) -> Vec<ConsoleCmd> {
+        let text = format!("{}\r\n", text.trim().to_lowercase());
+        match Self::many_commands(&text) {
+            Ok((remaining, commands)) => {
+                if !remaining.trim().is_empty() {
+                    tracing::warn!("couldn't parse command: {}", remaining.trim());
+                }
+
+                commands
+                    .into_iter()
+                    .map(|command| command.into_iter().map(str::to_owned).collect())
+                    .collect()
+            }
+            Err(e) => {
+                tracing::warn!("couldn't parse command: {:#}", e);
+                Vec::new()
+            }
+        }
+    }
+
     fn many_commands(input: &str) -> IResult<&str, Vec<Vec<&str>>> {
         delimited(
             many0(Self::empty_line),
@@ -158,24 +778,58 @@ impl Console {
     }
 
     fn argument(input: &str) -> IResult<&str, &str> {
-        alt((Self::quoted_argument, Self::basic_argument))(input)
+        alt((
+            Self::quoted_argument,
+            Self::expression_argument,
+            Self::basic_argument,
+        ))(input)
     }
 
+    // A single slash is part of the argument, such as in a file path, two of them start a comment.
     fn basic_argument(input: &str) -> IResult<&str, &str> {
-        take_while1(|c: char| !c.is_whitespace() && c != ';' && c != '/' && c != '\n' && c != '\r')(
-            input,
-        )
+        recognize(many1(alt((
+            is_not(" \t\r\n;/\""),
+            terminated(tag("/"), not(char('/'))),
+        ))))(input)
     }
 
+    // Quotes keep spaces and semicolons inside a single argument, they can be empty.  Only double quotes are quotes,
+    // as in Quake, so that apostrophes can be typed in unquoted text.
     fn quoted_argument(input: &str) -> IResult<&str, &str> {
-        alt((
-            delimited(tag("\""), is_not("\""), tag("\"")),
-            delimited(tag("'"), is_not("'"), tag("'")),
-        ))(input)
+        delimited(
+            char('"'),
+            map(opt(is_not("\"\r\n")), Option::unwrap_or_default),
+            char('"'),
+        )(input)
+    }
+
+    // Expressions are kept as a single argument including their spaces, they are evaluated when executed.
+    fn expression_argument(input: &str) -> IResult<&str, &str> {
+        recognize(preceded(tag("$"), Self::parenthesized))(input)
+    }
+
+    // Balanced parentheses on a single line, scanned without recursion so that deep nesting cannot overflow the stack.
+    fn parenthesized(input: &str) -> IResult<&str, &str> {
+        let mut depth = 0usize;
+        for (i, c) in input.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 1 => depth -= 1,
+                ')' if depth == 1 => return Ok((&input[i + 1..], &input[..=i])),
+                '\r' | '\n' => break,
+                _ if depth == 0 => break,
+                _ => (),
+            }
+        }
+
+        Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Char,
+        )))
     }
 
     fn command_terminator(input: &str) -> IResult<&str, &str> {
-        alt((tag(";"), Self::empty_line))(input)
+        alt((preceded(space0, tag(";")), Self::empty_line))(input)
     }
 
     fn empty_line(input: &str) -> IResult<&str, &str> {
@@ -187,6 +841,207 @@ impl Console {
     }
 }
 
+// Number of submitted lines remembered by the console input.
+const HISTORY_SIZE: usize = 32;
+
+// Line being typed into the console, with the lines submitted before it.
+#[derive(Default)]
+pub struct ConsoleInput {
+    line: Vec<char>,
+    cursor: usize,
+    history: VecDeque<String>,
+    // Position in the history while browsing it, the most recent line is at 0.
+    history_index: Option<usize>,
+    open: bool,
+    // Output lines scrolled back from the most recent one.
+    backscroll: usize,
+    // Text searched in the output, its matches are highlighted.
+    search: Option<String>,
+}
+
+impl ConsoleInput {
+    #[must_use]
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    #[must_use]
+    pub const fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    #[must_use]
+    pub const fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Shows or hides the console, it always opens at the most recent output line.
+    pub const fn toggle(&mut self) {
+        self.open = !self.open;
+        self.backscroll = 0;
+    }
+
+    #[must_use]
+    pub const fn backscroll(&self) -> usize {
+        self.backscroll
+    }
+
+    pub fn scroll_up(&mut self, lines: usize, console: &Console) {
+        self.backscroll = (self.backscroll + lines).min(console.output().len().saturating_sub(1));
+    }
+
+    pub const fn scroll_down(&mut self, lines: usize) {
+        self.backscroll = self.backscroll.saturating_sub(lines);
+    }
+
+    #[must_use]
+    pub fn search(&self) -> Option<&str> {
+        self.search.as_deref()
+    }
+
+    // Scrolls back to the most recent output line containing the text, ignoring case.  Searching the same text again
+    // goes on with the older lines.  Returns whether a line was found.
+    pub fn find(&mut self, text: &str, console: &Console) -> bool {
+        let text = text.to_lowercase();
+        let start = if self.search.as_ref() == Some(&text) {
+            self.backscroll + 1
+        } else {
+            0
+        };
+
+        let found = console
+            .output()
+            .lines()
+            .rev()
+            .enumerate()
+            .skip(start)
+            .find(|(_, (_, line))| line.to_lowercase().contains(&text));
+        self.search = Some(text);
+        if let Some((index, _)) = found {
+            self.backscroll = index;
+        }
+
+        found.is_some()
+    }
+
+    pub fn clear_search(&mut self) {
+        self.search = None;
+    }
+
+    pub fn insert(&mut self, c: char) {
+        self.line.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    // Removes the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    // Removes the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    pub const fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.line.len());
+    }
+
+    pub const fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub const fn end(&mut self) {
+        self.cursor = self.line.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.line.clear();
+        self.cursor = 0;
+        self.history_index = None;
+    }
+
+    // Replaces the line with an older one from the history.
+    pub fn history_up(&mut self) {
+        let index = self.history_index.map_or(0, |index| index + 1);
+        if let Some(line) = self.history.get(index) {
+            self.line = line.chars().collect();
+            self.cursor = self.line.len();
+            self.history_index = Some(index);
+        }
+    }
+
+    // Replaces the line with a newer one from the history, going past the most recent line empties it.
+    pub fn history_down(&mut self) {
+        match self.history_index {
+            Some(0) => self.clear(),
+            Some(index) => {
+                self.line = self.history[index - 1].chars().collect();
+                self.cursor = self.line.len();
+                self.history_index = Some(index - 1);
+            }
+            None => (),
+        }
+    }
+
+    // Completes the first word of the line with the names known by the console, as far as they have in common.
+    pub fn complete(&mut self, console: &Console) {
+        let line = self.line();
+        if line.contains(char::is_whitespace) {
+            return;
+        }
+
+        let names = console.complete(&line);
+        let Some(first) = names.first() else {
+            return;
+        };
+
+        let mut common = first.clone();
+        for name in &names[1..] {
+            let length = common
+                .chars()
+                .zip(name.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            common = common.chars().take(length).collect();
+        }
+        if names.len() == 1 {
+            common.push(' ');
+        }
+
+        self.line = common.chars().collect();
+        self.cursor = self.line.len();
+    }
+
+    // Sends the line to the console and remembers it.
+    pub fn submit(&mut self, console: &mut Console) {
+        let line = self.line();
+        if !line.trim().is_empty() {
+            console.push_command(&line);
+
+            if self.history.front() != Some(&line) {
+                self.history.push_front(line);
+                self.history.truncate(HISTORY_SIZE);
+            }
+        }
+        self.clear();
+        self.backscroll = 0;
+    }
+}
+
 #[system]
 pub fn console_command_preprocessor(
     #[resource] console: &mut Console,
@@ -195,13 +1050,269 @@ pub fn console_command_preprocessor(
     let mut command_queue = VecDeque::new();
     command_queue.extend(console.command_queue.drain(..));
     for command in command_queue {
-        if let Some(commands) = console.execute_command(&command, resource_files) {
+        if let Some(commands) = console.execute_command(&command, resource_files, 0) {
             console.command_queue.extend(commands);
         }
     }
 }
 
+pub fn write_config_file(
+    console: &Console,
+    input: &Input,
+    resource_files: &ResourceFiles,
+) -> anyhow::Result<()> {
+    let file_path = resource_files.dir_path().join("config.cfg");
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    console.write_config(&mut writer, input)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[system]
+pub fn console_config_writer(
+    #[resource] console: &Console,
+    #[resource] input: &Input,
+    #[resource] resource_files: &ResourceFiles,
+) {
+    // Writes the current configuration to config.cfg in the game directory.
+    if console
+        .commands()
+        .any(|command| command[0] == "host_writeconfig")
+    {
+        if let Err(e) = write_config_file(console, input, resource_files) {
+            tracing::warn!("couldn't write config.cfg: {:#}", e);
+        }
+    }
+}
+
+#[system]
+pub fn console_input_command_executor(
+    #[resource] console: &mut Console,
+    #[resource] console_input: &mut ConsoleInput,
+    #[resource] input: &mut Input,
+) {
+    let mut missing = Vec::new();
+    console.commands().for_each(|command| match &command[..] {
+        // Shows or hides the console, the keyboard goes to it while it is open.
+        [ref cmd] if cmd == "toggleconsole" => {
+            console_input.toggle();
+            input.set_focus(if console_input.is_open() {
+                InputFocus::Console
+            } else {
+                InputFocus::Game
+            });
+        }
+        // Scrolls the console back to the last line containing the text and highlights its matches, repeating the
+        // search goes further back.  Without text the highlighting is removed.
+        [ref cmd, text @ ..] if cmd == "search" => {
+            let text = text.join(" ");
+            if text.is_empty() {
+                console_input.clear_search();
+            } else if !console_input.find(&text, console) {
+                missing.push(text);
+            }
+        }
+        _ => (),
+    });
+
+    for text in missing {
+        console.print_warning(&format!("no more lines matching \"{text}\""));
+    }
+}
+
 #[system]
 pub fn console_command_postprocessor(#[resource] console: &mut Console) {
     console.command_queue.clear();
+    console.changed_variables.clear();
+    console.executed_commands = 0;
+    console.errors.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warnings(console: &Console) -> Vec<&str> {
+        console
+            .output()
+            .lines()
+            .filter(|(level, _)| *level == OutputLevel::Warning)
+            .map(|(_, line)| line)
+            .collect()
+    }
+
+    // Runs the queued commands the way the console does in a frame, and returns those left for the other systems.
+    fn run(console: &mut Console, text: &str) -> VecDeque<ConsoleCmd> {
+        let mut resource_files = ResourceFiles::new("no-game-dir").unwrap();
+        console.push_command(text);
+        let mut command_queue = VecDeque::new();
+        command_queue.extend(console.command_queue.drain(..));
+        for command in command_queue {
+            if let Some(commands) = console.execute_command(&command, &mut resource_files, 0) {
+                console.command_queue.extend(commands);
+            }
+        }
+
+        console.command_queue.clone()
+    }
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let console = Console::default();
+        assert_eq!(console.evaluate("$(1 + 2 * 3)").unwrap(), "7");
+        assert_eq!(console.evaluate("$((1 + 2) * 3)").unwrap(), "9");
+        assert_eq!(console.evaluate("$(10 - 4 - 3)").unwrap(), "3");
+        assert_eq!(console.evaluate("$(12 / 3 / 2)").unwrap(), "2");
+    }
+
+    #[test]
+    fn expressions_negate() {
+        let console = Console::default();
+        assert_eq!(console.evaluate("$(-2 * -3)").unwrap(), "6");
+        assert_eq!(console.evaluate("$(-(1 + 2))").unwrap(), "-3");
+        assert_eq!(console.evaluate("$(4 - -1)").unwrap(), "5");
+    }
+
+    #[test]
+    fn expressions_read_variables() {
+        let mut console = Console::default();
+        console.set_var("fov", "90");
+        console.set_var("name", "player");
+        assert_eq!(console.evaluate("$(fov + 5)").unwrap(), "95");
+        assert!(console.evaluate("$(name + 5)").is_err());
+        assert!(console.evaluate("$(missing)").is_err());
+    }
+
+    #[test]
+    fn expressions_reject_division_by_zero() {
+        let console = Console::default();
+        assert!(console.evaluate("$(1 / 0)").is_err());
+        assert!(console.evaluate("$(0 / 0)").is_err());
+
+        let mut console = Console::default();
+        run(&mut console, "set baz \"$((1+2)*(3-1)/0)\"");
+        assert!(console.get_cvar("baz").is_none());
+        assert_eq!(
+            warnings(&console),
+            ["expression has no finite value: (1+2)*(3-1)/0"]
+        );
+    }
+
+    #[test]
+    fn expressions_reject_malformed_input() {
+        let console = Console::default();
+        for expression in [
+            "$()",
+            "$(1 +)",
+            "$(1 2)",
+            "$((1 + 2)",
+            "$(1 + 2))",
+            "$(* 2)",
+        ] {
+            assert!(console.evaluate(expression).is_err(), "{expression}");
+        }
+        let long = format!("$({})", "1+".repeat(MAX_EXPRESSION_LENGTH) + "1");
+        assert!(console.evaluate(&long).is_err());
+
+        // Anything that isn't an expression is kept as it is.
+        assert_eq!(console.evaluate("1 + 2").unwrap(), "1 + 2");
+    }
+
+    #[test]
+    fn self_referencing_alias_is_stopped() {
+        let mut console = Console::default();
+        run(&mut console, "alias a \"a\"");
+        let remaining = run(&mut console, "a");
+
+        assert!(remaining.is_empty());
+        assert_eq!(warnings(&console), ["a nested too deep, ignored"]);
+        assert_eq!(
+            console.errors().collect::<Vec<_>>(),
+            [&ConsoleError::RecursionTooDeep {
+                command: "a".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn mutually_recursive_aliases_are_stopped() {
+        let mut console = Console::default();
+        run(&mut console, "alias a b; alias b a");
+        let remaining = run(&mut console, "a");
+
+        // The alias run at the first depth past the limit is the one reported.
+        assert!(remaining.is_empty());
+        assert_eq!(warnings(&console), ["b nested too deep, ignored"]);
+        assert_eq!(
+            console.errors().collect::<Vec<_>>(),
+            [&ConsoleError::RecursionTooDeep {
+                command: "b".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn commands_past_the_frame_budget_are_dropped() {
+        let mut console = Console::default();
+        let script = vec!["wait"; MAX_COMMANDS_PER_FRAME + 10].join(";");
+        let remaining = run(&mut console, &script);
+
+        assert_eq!(remaining.len(), MAX_COMMANDS_PER_FRAME);
+        assert_eq!(
+            warnings(&console),
+            ["too many commands in a frame, wait and the rest are ignored"]
+        );
+        assert_eq!(
+            console.errors().collect::<Vec<_>>(),
+            [&ConsoleError::BudgetExceeded {
+                command: "wait".to_owned()
+            }]
+        );
+    }
+
+    fn commands(lines: &[&[&str]]) -> Vec<ConsoleCmd> {
+        lines
+            .iter()
+            .map(|line| line.iter().map(|arg| (*arg).to_owned()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn quotes_keep_spaces_in_one_argument() {
+        assert_eq!(
+            Console::tokenize("echo \"hello  world\" \"\" end"),
+            commands(&[&["echo", "hello  world", "", "end"]])
+        );
+        // Apostrophes are plain text, only double quotes quote.
+        assert_eq!(
+            Console::tokenize("echo don't stop"),
+            commands(&[&["echo", "don't", "stop"]])
+        );
+    }
+
+    #[test]
+    fn semicolons_in_quotes_dont_split_commands() {
+        assert_eq!(
+            Console::tokenize("bind k \"+attack; wait; -attack\"; echo done"),
+            commands(&[&["bind", "k", "+attack; wait; -attack"], &["echo", "done"]])
+        );
+        assert_eq!(
+            Console::tokenize("echo a;echo b\necho c // comment"),
+            commands(&[&["echo", "a"], &["echo", "b"], &["echo", "c"]])
+        );
+    }
+
+    #[test]
+    fn expressions_are_one_argument() {
+        assert_eq!(
+            Console::tokenize("set fov $( (fov + 5) * 2 ); echo $x"),
+            commands(&[&["set", "fov", "$( (fov + 5) * 2 )"], &["echo", "$x"]])
+        );
+        // Without its closing parenthesis it is not an expression, only plain words.
+        assert_eq!(
+            Console::tokenize("set fov $(fov + 5"),
+            commands(&[&["set", "fov", "$(fov", "+", "5"]])
+        );
+    }
 }
@@ -0,0 +1,55 @@
+use std::io::Cursor;
+
+use crate::{
+    console::Console,
+    message::{FileMessageStream, MessageStream},
+    picture::{Picture, Wad},
+    Pack,
+};
+
+// Entry points for the fuzz targets in fuzz/.  Each one feeds untrusted bytes to a parser and walks everything it
+// returns, errors are expected and ignored but nothing may panic.
+
+pub fn pack(data: &[u8]) {
+    let Ok(mut pack) = Pack::new(Cursor::new(data.to_vec())) else {
+        return;
+    };
+
+    let file_names: Vec<String> = pack.file_names().map(str::to_owned).collect();
+    for file_name in file_names {
+        let _ = pack.take(&file_name);
+    }
+}
+
+pub fn demo(data: &[u8]) {
+    let Ok(mut stream) = FileMessageStream::new(Cursor::new(data.to_vec())) else {
+        return;
+    };
+
+    while let Ok(Some(_)) = stream.next() {}
+}
+
+pub fn console(data: &[u8]) {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let console = Console::default();
+    for command in Console::tokenize(text) {
+        for argument in command {
+            let _ = console.evaluate(&argument);
+        }
+    }
+}
+
+pub fn picture(data: &[u8]) {
+    let _ = Picture::from_lmp(Cursor::new(data));
+}
+
+pub fn wad(data: &[u8]) {
+    let Ok(wad) = Wad::new(Cursor::new(data)) else {
+        return;
+    };
+
+    let _ = wad.conchars();
+}
@@ -1,11 +1,611 @@
+use anyhow::bail;
 use legion::system;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    console::Console,
+    picture::{Palette, Picture},
+};
+
+// Width the 2D canvas aims for, pictures and text are scaled by whole numbers to get close to it.
+const CANVAS_WIDTH: u32 = 640;
+const CANVAS_HEIGHT: u32 = 400;
+
+// Depth buffer shared by every pass of the frame.
+const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+// Depth state of the pipelines drawn in the main pass.  Opaque geometry tests and writes depth, translucent geometry
+// only tests it so that what is behind it still shows, and the 2D overlay ignores it.
+fn depth_state(write: bool, compare: wgpu::CompareFunction) -> wgpu::DepthStencilState {
+    wgpu::DepthStencilState {
+        format: DEPTH_FORMAT,
+        depth_write_enabled: write,
+        depth_compare: compare,
+        stencil: wgpu::StencilState::default(),
+        bias: wgpu::DepthBiasState::default(),
+    }
+}
+
+fn create_depth_view(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some("depth"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+// Position, texture coordinates and color of a 2D vertex.
+const OVERLAY_VERTEX_SIZE: usize = 8;
+
+const OVERLAY_SHADER: &str = r"
+struct Canvas {
+    size: vec2<f32>,
+};
+
+@group(0) @binding(0) var<uniform> canvas: Canvas;
+@group(1) @binding(0) var picture_texture: texture_2d<f32>;
+@group(1) @binding(1) var picture_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(
+    @location(0) position: vec2<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) color: vec4<f32>,
+) -> VertexOutput {
+    var out: VertexOutput;
+    let ndc = position / canvas.size * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0);
+    out.position = vec4<f32>(ndc, 0.0, 1.0);
+    out.uv = uv;
+    out.color = color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return textureSample(picture_texture, picture_sampler, in.uv) * in.color;
+}
+";
+
+const POST_SHADER: &str = r"
+struct ColorCorrection {
+    gamma: f32,
+    contrast: f32,
+    srgb: f32,
+};
+
+@group(0) @binding(0) var scene_texture: texture_2d<f32>;
+@group(0) @binding(1) var scene_sampler: sampler;
+@group(0) @binding(2) var<uniform> correction: ColorCorrection;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// A single triangle covering the whole screen.
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    out.position = vec4<f32>(uv * vec2<f32>(2.0, -2.0) + vec2<f32>(-1.0, 1.0), 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn encode_srgb(color: vec3<f32>) -> vec3<f32> {
+    return select(1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055, color * 12.92, color <= vec3<f32>(0.0031308));
+}
+
+fn decode_srgb(color: vec3<f32>) -> vec3<f32> {
+    return select(pow((color + 0.055) / 1.055, vec3<f32>(2.4)), color / 12.92, color <= vec3<f32>(0.04045));
+}
+
+// The correction applies to gamma encoded colors like the palette of the original engine, sRGB targets are decoded
+// and encoded around it.
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = textureSample(scene_texture, scene_sampler, in.uv).rgb;
+    if correction.srgb != 0.0 {
+        color = encode_srgb(color);
+    }
+    color = clamp(pow(color, vec3<f32>(correction.gamma)) * correction.contrast, vec3<f32>(0.0), vec3<f32>(1.0));
+    if correction.srgb != 0.0 {
+        color = decode_srgb(color);
+    }
+    return vec4<f32>(color, 1.0);
+}
+";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PictureId(usize);
+
+struct OverlayPicture {
+    width: u32,
+    height: u32,
+    bind_group: wgpu::BindGroup,
+}
+
+// Screen space pictures, text and filled rectangles drawn on top of the frame.  Everything is queued during the
+// frame and flushed at present time in the order it was drawn.
+struct Overlay {
+    pipeline: wgpu::RenderPipeline,
+    canvas_buffer: wgpu::Buffer,
+    canvas_bind_group: wgpu::BindGroup,
+    picture_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+
+    pictures: Vec<OverlayPicture>,
+    white: PictureId,
+
+    vertices: Vec<f32>,
+    batches: Vec<(PictureId, std::ops::Range<u32>)>,
+}
+
+impl Overlay {
+    #[allow(clippy::too_many_lines)]
+    fn new(device: &wgpu::Device, queue: &wgpu::Queue, format: wgpu::TextureFormat) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("overlay"),
+            source: wgpu::ShaderSource::Wgsl(OVERLAY_SHADER.into()),
+        });
+
+        let canvas_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("overlay canvas"),
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let canvas_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("overlay canvas"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let canvas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("overlay canvas"),
+            layout: &canvas_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: canvas_buffer.as_entire_binding(),
+            }],
+        });
+
+        let picture_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("overlay picture"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("overlay"),
+            ..Default::default()
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("overlay"),
+            bind_group_layouts: &[&canvas_layout, &picture_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("overlay"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[wgpu::VertexBufferLayout {
+                    array_stride: (OVERLAY_VERTEX_SIZE * std::mem::size_of::<f32>()) as u64,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: &wgpu::vertex_attr_array![
+                        0 => Float32x2,
+                        1 => Float32x2,
+                        2 => Float32x4,
+                    ],
+                }],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: Some(depth_state(false, wgpu::CompareFunction::Always)),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let mut overlay = Self {
+            pipeline,
+            canvas_buffer,
+            canvas_bind_group,
+            picture_layout,
+            sampler,
+            pictures: Vec::new(),
+            white: PictureId(0),
+            vertices: Vec::new(),
+            batches: Vec::new(),
+        };
+        overlay.white = overlay.upload(device, queue, format, 1, 1, &[255; 4]);
+
+        overlay
+    }
+
+    fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> PictureId {
+        // Palette colors are already gamma encoded, the texture must match the surface so that they are not encoded
+        // a second time when written to it.
+        let texture_format = if format.is_srgb() {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        };
+        let texture = device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("overlay picture"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: texture_format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            wgpu::util::TextureDataOrder::LayerMajor,
+            rgba,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("overlay picture"),
+            layout: &self.picture_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&self.sampler),
+                },
+            ],
+        });
+
+        self.pictures.push(OverlayPicture {
+            width,
+            height,
+            bind_group,
+        });
+
+        PictureId(self.pictures.len() - 1)
+    }
+
+    fn push_quad(&mut self, picture: PictureId, rect: [f32; 4], uv: [f32; 4], color: [f32; 4]) {
+        let [x, y, w, h] = rect;
+        let [u0, v0, u1, v1] = uv;
+        let corners = [
+            [x, y, u0, v0],
+            [x + w, y, u1, v0],
+            [x, y + h, u0, v1],
+            [x, y + h, u0, v1],
+            [x + w, y, u1, v0],
+            [x + w, y + h, u1, v1],
+        ];
+
+        #[allow(clippy::cast_possible_truncation)]
+        let first = (self.vertices.len() / OVERLAY_VERTEX_SIZE) as u32;
+        for corner in corners {
+            self.vertices.extend_from_slice(&corner);
+            self.vertices.extend_from_slice(&color);
+        }
+
+        match self.batches.last_mut() {
+            Some((last, range)) if *last == picture => range.end += 6,
+            _ => self.batches.push((picture, first..first + 6)),
+        }
+    }
+
+    fn draw(&self, device: &wgpu::Device, render_pass: &mut wgpu::RenderPass<'_>) {
+        if self.batches.is_empty() {
+            return;
+        }
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("overlay vertices"),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.canvas_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+        for (picture, range) in &self.batches {
+            render_pass.set_bind_group(1, &self.pictures[picture.0].bind_group, &[]);
+            render_pass.draw(range.clone(), 0..1);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.vertices.clear();
+        self.batches.clear();
+    }
+}
+
+// Full screen pass copying the frame to the surface with the gamma and contrast correction, surfaces give no control
+// over the hardware gamma ramp.  Everything else is drawn to the scene texture first.
+struct PostProcess {
+    pipeline: wgpu::RenderPipeline,
+    layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    correction_buffer: wgpu::Buffer,
+    srgb: bool,
+
+    scene_view: wgpu::TextureView,
+    bind_group: wgpu::BindGroup,
+}
+
+impl PostProcess {
+    fn new(device: &wgpu::Device, format: wgpu::TextureFormat, width: u32, height: u32) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("post"),
+            source: wgpu::ShaderSource::Wgsl(POST_SHADER.into()),
+        });
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("post"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post"),
+            ..Default::default()
+        });
+
+        let srgb = format.is_srgb();
+        let correction_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("post correction"),
+            contents: bytemuck::cast_slice(&[1.0f32, 1.0, if srgb { 1.0 } else { 0.0 }, 0.0]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("post"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("post"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let (scene_view, bind_group) = Self::create_scene(
+            device,
+            &layout,
+            &sampler,
+            &correction_buffer,
+            format,
+            width,
+            height,
+        );
+
+        Self {
+            pipeline,
+            layout,
+            sampler,
+            correction_buffer,
+            srgb,
+            scene_view,
+            bind_group,
+        }
+    }
+
+    // The scene texture has the size and the format of the surface, so the pipelines drawing to either are the same.
+    fn create_scene(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        correction_buffer: &wgpu::Buffer,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> (wgpu::TextureView, wgpu::BindGroup) {
+        let scene_view = device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("scene"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("post"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&scene_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: correction_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        (scene_view, bind_group)
+    }
+
+    fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) {
+        (self.scene_view, self.bind_group) = Self::create_scene(
+            device,
+            &self.layout,
+            &self.sampler,
+            &self.correction_buffer,
+            format,
+            width,
+            height,
+        );
+    }
+
+    fn set_correction(&self, queue: &wgpu::Queue, gamma: f32, contrast: f32) {
+        let srgb = if self.srgb { 1.0 } else { 0.0 };
+        queue.write_buffer(
+            &self.correction_buffer,
+            0,
+            bytemuck::cast_slice(&[gamma, contrast, srgb, 0.0]),
+        );
+    }
+
+    fn draw(&self, render_pass: &mut wgpu::RenderPass<'_>) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+// Work done by the last presented frame.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RenderStats {
+    pub draw_calls: usize,
+    pub quads: usize,
+    // Pictures uploaded to the GPU since the frame before.
+    pub uploads: usize,
+}
 
 pub struct Graphics {
     surface: wgpu::Surface<'static>,
     surface_config: wgpu::SurfaceConfiguration,
+    depth_view: wgpu::TextureView,
 
     device: wgpu::Device,
     queue: wgpu::Queue,
+
+    overlay: Overlay,
+    post: PostProcess,
+
+    stats: RenderStats,
+    uploads: usize,
 }
 
 impl Graphics {
@@ -34,17 +634,182 @@ impl Graphics {
             .await?;
         let surface_config = surface.get_default_config(&adapter, width, height).unwrap();
         surface.configure(&device, &surface_config);
+        let depth_view = create_depth_view(&device, surface_config.width, surface_config.height);
+
+        let overlay = Overlay::new(&device, &queue, surface_config.format);
+        let post = PostProcess::new(
+            &device,
+            surface_config.format,
+            surface_config.width,
+            surface_config.height,
+        );
 
         Ok(Self {
             surface,
             surface_config,
+            depth_view,
             device,
             queue,
+            overlay,
+            post,
+            stats: RenderStats::default(),
+            uploads: 0,
         })
     }
 
+    // The surface, the scene and the depth buffer follow the size of the window, a minimized window keeps the last
+    // ones.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        self.surface_config.width = width;
+        self.surface_config.height = height;
+        self.surface.configure(&self.device, &self.surface_config);
+        self.depth_view = create_depth_view(&self.device, width, height);
+        self.post
+            .resize(&self.device, self.surface_config.format, width, height);
+    }
+
+    // Without vertical sync frames are presented right away, or replace the one waiting when tearing can't be had.
+    pub fn set_vsync(&mut self, vsync: bool) {
+        self.surface_config.present_mode = if vsync {
+            wgpu::PresentMode::AutoVsync
+        } else {
+            wgpu::PresentMode::AutoNoVsync
+        };
+        self.surface.configure(&self.device, &self.surface_config);
+    }
+
+    // Colors are raised to the power of the gamma and multiplied by the contrast, both 1 leave them untouched.
+    pub fn set_color_correction(&self, gamma: f32, contrast: f32) {
+        self.post.set_correction(&self.queue, gamma, contrast);
+    }
+
+    // Pictures come from the game data, a size the device can't make a texture of is refused instead of failing the
+    // validation of wgpu.
+    pub fn upload_picture(
+        &mut self,
+        picture: &Picture,
+        palette: &Palette,
+    ) -> anyhow::Result<PictureId> {
+        let max_dimension = self.device.limits().max_texture_dimension_2d;
+        if !(1..=max_dimension).contains(&picture.width())
+            || !(1..=max_dimension).contains(&picture.height())
+        {
+            bail!(
+                "invalid picture size {}x{}",
+                picture.width(),
+                picture.height()
+            );
+        }
+
+        self.uploads += 1;
+        Ok(self.overlay.upload(
+            &self.device,
+            &self.queue,
+            self.surface_config.format,
+            picture.width(),
+            picture.height(),
+            &picture.to_rgba(palette),
+        ))
+    }
+
+    // Number of pictures uploaded and the bytes taken by their textures.
+    #[must_use]
+    pub fn texture_usage(&self) -> (usize, usize) {
+        let bytes = self
+            .overlay
+            .pictures
+            .iter()
+            .map(|picture| picture.width as usize * picture.height as usize * 4)
+            .sum();
+
+        (self.overlay.pictures.len(), bytes)
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    #[must_use]
+    pub fn picture_size(&self, picture: PictureId) -> (f32, f32) {
+        let picture = &self.overlay.pictures[picture.0];
+        #[allow(clippy::cast_precision_loss)]
+        (picture.width as f32, picture.height as f32)
+    }
+
+    // Pictures are scaled by the largest whole number that keeps the canvas at least as big as the original 640x400
+    // console, so that they stay crisp at any resolution.
+    #[must_use]
+    pub fn canvas_scale(&self) -> u32 {
+        (self.surface_config.width / CANVAS_WIDTH)
+            .min(self.surface_config.height / CANVAS_HEIGHT)
+            .max(1)
+    }
+
+    #[must_use]
+    pub fn canvas_size(&self) -> (f32, f32) {
+        let scale = self.canvas_scale();
+        #[allow(clippy::cast_precision_loss)]
+        (
+            (self.surface_config.width / scale) as f32,
+            (self.surface_config.height / scale) as f32,
+        )
+    }
+
+    pub fn draw_picture(&mut self, picture: PictureId, x: f32, y: f32) {
+        let (width, height) = self.picture_size(picture);
+        self.overlay.push_quad(
+            picture,
+            [x, y, width, height],
+            [0.0, 0.0, 1.0, 1.0],
+            [1.0; 4],
+        );
+    }
+
+    // Draws the part of a picture given in texture coordinates, used to pick characters out of a font.  Its colors are
+    // multiplied by the given color.
+    pub fn draw_sub_picture(
+        &mut self,
+        picture: PictureId,
+        rect: [f32; 4],
+        uv: [f32; 4],
+        color: [f32; 4],
+    ) {
+        self.overlay.push_quad(picture, rect, uv, color);
+    }
+
+    pub fn fill_rect(&mut self, rect: [f32; 4], color: [f32; 4]) {
+        self.overlay
+            .push_quad(self.overlay.white, rect, [0.0, 0.0, 1.0, 1.0], color);
+    }
+
     pub fn present(&mut self) -> anyhow::Result<()> {
-        let surface_texture = self.surface.get_current_texture()?;
+        let (canvas_width, canvas_height) = self.canvas_size();
+        self.queue.write_buffer(
+            &self.overlay.canvas_buffer,
+            0,
+            bytemuck::cast_slice(&[canvas_width, canvas_height, 0.0, 0.0]),
+        );
+
+        // A surface that no longer matches the window, after a resize, a video mode switch or while minimized, is
+        // configured again and the frame is dropped.
+        let surface_texture = match self.surface.get_current_texture() {
+            Ok(surface_texture) => surface_texture,
+            Err(wgpu::SurfaceError::Outdated | wgpu::SurfaceError::Lost) => {
+                self.surface.configure(&self.device, &self.surface_config);
+                self.overlay.clear();
+                return Ok(());
+            }
+            Err(wgpu::SurfaceError::Timeout) => {
+                self.overlay.clear();
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
         let surface_view = surface_texture
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
@@ -54,24 +819,72 @@ impl Graphics {
         {
             let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &surface_view,
+                    view: &self.post.scene_view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color::BLUE),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Discard,
+                    }),
+                    stencil_ops: None,
+                }),
+                ..Default::default()
+            });
+            self.overlay.draw(&self.device, &mut render_pass);
+        }
+        {
+            let mut render_pass = command_encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &surface_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: wgpu::StoreOp::Store,
+                    },
+                })],
                 ..Default::default()
             });
+            self.post.draw(&mut render_pass);
         }
         self.queue.submit(std::iter::once(command_encoder.finish()));
         surface_texture.present();
 
+        self.stats = RenderStats {
+            draw_calls: self.overlay.batches.len(),
+            quads: self.overlay.vertices.len() / (OVERLAY_VERTEX_SIZE * 6),
+            uploads: std::mem::take(&mut self.uploads),
+        };
+        self.overlay.clear();
+
         Ok(())
     }
 }
 
 #[system]
 pub fn graphics_present(#[resource] graphics: &mut Graphics) {
-    graphics.present().unwrap();
+    if let Err(e) = graphics.present() {
+        tracing::error!("couldn't present the frame: {:#}", e);
+    }
+}
+
+#[system]
+pub fn graphics_update(#[resource] graphics: &mut Graphics, #[resource] console: &Console) {
+    if console.changed_vars().any(|name| name == "vid_vsync") {
+        let vsync = console.get_var::<i32>("vid_vsync").unwrap_or(1);
+        graphics.set_vsync(vsync != 0);
+    }
+    if console
+        .changed_vars()
+        .any(|name| name == "gamma" || name == "contrast")
+    {
+        let gamma = console.get_var::<f32>("gamma").unwrap_or(1.0);
+        let contrast = console.get_var::<f32>("contrast").unwrap_or(1.0);
+        graphics.set_color_correction(gamma.clamp(0.5, 1.0), contrast.clamp(1.0, 2.0));
+    }
 }
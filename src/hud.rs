@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+
+use crate::{message::ServerMessage, screen::highlight};
+
+// Notify lines shown at once, older ones are dropped first.
+pub(crate) const NOTIFY_LINES: usize = 4;
+// Seconds a notify line stays on screen.
+const NOTIFY_TIME: f32 = 3.0;
+// Seconds a centered message stays on screen, the last part of it fading out.
+const CENTER_TIME: f32 = 2.0;
+const CENTER_FADE: f32 = 0.5;
+// Seconds the pain face is shown after taking damage.
+const PAIN_FACE_TIME: f32 = 0.2;
+
+// Indices of the player stats sent by the server.
+pub const STAT_HEALTH: usize = 0;
+pub const STAT_FRAGS: usize = 1;
+pub const STAT_WEAPON: usize = 2;
+pub const STAT_AMMO: usize = 3;
+pub const STAT_ARMOR: usize = 4;
+pub const STAT_WEAPONFRAME: usize = 5;
+pub const STAT_SHELLS: usize = 6;
+pub const STAT_NAILS: usize = 7;
+pub const STAT_ROCKETS: usize = 8;
+pub const STAT_CELLS: usize = 9;
+pub const STAT_ACTIVEWEAPON: usize = 10;
+pub const STAT_TOTALSECRETS: usize = 11;
+pub const STAT_TOTALMONSTERS: usize = 12;
+pub const STAT_SECRETS: usize = 13;
+pub const STAT_MONSTERS: usize = 14;
+const MAX_STATS: usize = 32;
+
+// Bits of the player items.
+pub const IT_SHOTGUN: u32 = 1 << 0;
+pub const IT_SHELLS: u32 = 1 << 8;
+pub const IT_NAILS: u32 = 1 << 9;
+pub const IT_ROCKETS: u32 = 1 << 10;
+pub const IT_CELLS: u32 = 1 << 11;
+pub const IT_ARMOR1: u32 = 1 << 13;
+pub const IT_ARMOR2: u32 = 1 << 14;
+pub const IT_ARMOR3: u32 = 1 << 15;
+pub const IT_KEY1: u32 = 1 << 17;
+pub const IT_INVISIBILITY: u32 = 1 << 19;
+pub const IT_INVULNERABILITY: u32 = 1 << 20;
+pub const IT_QUAD: u32 = 1 << 22;
+pub const IT_SIGIL1: u32 = 1 << 28;
+
+// State of the player shown by the status bar, as last sent by the server.
+#[derive(Default)]
+pub struct PlayerStats {
+    stats: [i32; MAX_STATS],
+    items: u32,
+    // World time until which the pain face is shown.
+    pain_time: f32,
+}
+
+impl PlayerStats {
+    #[must_use]
+    pub fn stat(&self, index: usize) -> i32 {
+        self.stats.get(index).copied().unwrap_or(0)
+    }
+
+    #[must_use]
+    pub const fn items(&self) -> u32 {
+        self.items
+    }
+
+    #[must_use]
+    pub const fn has_item(&self, item: u32) -> bool {
+        self.items & item != 0
+    }
+
+    #[must_use]
+    pub fn in_pain(&self, now: f32) -> bool {
+        now < self.pain_time
+    }
+
+    fn handle_server_message(&mut self, message: &ServerMessage, now: f32) {
+        match *message {
+            ServerMessage::UpdateStat { index, value } => {
+                if let Some(stat) = self.stats.get_mut(usize::from(index)) {
+                    *stat = value;
+                }
+            }
+            ServerMessage::PlayerData {
+                items,
+                weaponframe,
+                armorvalue,
+                weaponmodel,
+                health,
+                currentammo,
+                ammo_shells,
+                ammo_nails,
+                ammo_rockets,
+                ammo_cells,
+                weapon,
+                ..
+            } => {
+                self.items = items;
+                self.stats[STAT_WEAPONFRAME] = i32::from(weaponframe);
+                self.stats[STAT_ARMOR] = i32::from(armorvalue);
+                self.stats[STAT_WEAPON] = i32::from(weaponmodel);
+                self.stats[STAT_HEALTH] = i32::from(health);
+                self.stats[STAT_AMMO] = i32::from(currentammo);
+                self.stats[STAT_SHELLS] = i32::from(ammo_shells);
+                self.stats[STAT_NAILS] = i32::from(ammo_nails);
+                self.stats[STAT_ROCKETS] = i32::from(ammo_rockets);
+                self.stats[STAT_CELLS] = i32::from(ammo_cells);
+                self.stats[STAT_ACTIVEWEAPON] = i32::from(weapon);
+            }
+            ServerMessage::Damage { .. } => self.pain_time = now + PAIN_FACE_TIME,
+            // The counters come from untrusted demos, which could have set them to anything.
+            ServerMessage::KilledMonster => {
+                self.stats[STAT_MONSTERS] = self.stats[STAT_MONSTERS].saturating_add(1);
+            }
+            ServerMessage::FoundSecret => {
+                self.stats[STAT_SECRETS] = self.stats[STAT_SECRETS].saturating_add(1);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Messages the server prints over the world and the state of the player, timed with the world clock so that they stay on screen while paused.
+#[derive(Default)]
+pub struct Hud {
+    // Lines printed at the top of the screen, with the time they were printed.
+    notify: VecDeque<(f32, String)>,
+    center: Option<(f32, String)>,
+    stats: PlayerStats,
+    // The status bar is only shown once the server sent the state of the player.
+    stats_received: bool,
+}
+
+impl Hud {
+    // Lines starting with the marker characters of the original engine are printed in the alternate color.
+    pub fn notify(&mut self, text: &str, now: f32) {
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let line = line
+                .strip_prefix(['\u{1}', '\u{2}'])
+                .map_or_else(|| line.to_owned(), highlight);
+
+            if self.notify.len() == NOTIFY_LINES {
+                self.notify.pop_front();
+            }
+            self.notify.push_back((now, line));
+        }
+    }
+
+    pub fn center_print(&mut self, text: &str, now: f32) {
+        self.center = (!text.is_empty()).then(|| (now, text.to_owned()));
+    }
+
+    pub fn clear(&mut self) {
+        self.notify.clear();
+        self.center = None;
+        self.stats = PlayerStats::default();
+        self.stats_received = false;
+    }
+
+    #[must_use]
+    pub const fn stats(&self) -> Option<&PlayerStats> {
+        if self.stats_received {
+            Some(&self.stats)
+        } else {
+            None
+        }
+    }
+
+    // Notify lines that have not expired yet, from the oldest to the most recent.
+    pub fn notify_lines(&self, now: f32) -> impl Iterator<Item = &str> {
+        self.notify
+            .iter()
+            .filter(move |(time, _)| now - time < NOTIFY_TIME)
+            .map(|(_, line)| line.as_str())
+    }
+
+    // Centered message with its opacity, if it has not expired yet.
+    #[must_use]
+    pub fn center_text(&self, now: f32) -> Option<(&str, f32)> {
+        let (time, text) = self.center.as_ref()?;
+        let remaining = CENTER_TIME - (now - time);
+        (remaining > 0.0).then(|| (text.as_str(), (remaining / CENTER_FADE).min(1.0)))
+    }
+
+    pub fn handle_server_message(&mut self, message: &ServerMessage, now: f32) {
+        match message {
+            ServerMessage::Print { text } => self.notify(text, now),
+            ServerMessage::CenterPrint { text } => self.center_print(text, now),
+            // Nothing printed for the previous level is left over.
+            ServerMessage::ServerInfo { .. } | ServerMessage::Disconnect => self.clear(),
+            _ => {
+                self.stats_received |= matches!(message, ServerMessage::PlayerData { .. });
+                self.stats.handle_server_message(message, now);
+            }
+        }
+    }
+}
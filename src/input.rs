@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use legion::system;
 use winit::{
@@ -7,13 +7,15 @@ use winit::{
     keyboard::KeyCode,
 };
 
-use crate::console::{Console, ConsoleCmd};
+use crate::console::{Console, ConsoleCmd, ConsoleInput};
 
 #[derive(Clone, Copy, Debug)]
 pub enum InputEvent {
     KeyboardInput {
         code: KeyCode,
         state: ElementState,
+        // Character typed by the key with the current layout and modifiers.
+        text: Option<char>,
     },
     MouseInput {
         button: MouseButton,
@@ -22,11 +24,62 @@ pub enum InputEvent {
     MouseWheel {
         delta: MouseScrollDelta,
     },
+    // Character committed by an input method, such as one composed out of several keys.
+    Text {
+        text: char,
+    },
+}
+
+// Where the keyboard goes: to the key bindings, or to the line typed in the console or in a chat message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InputFocus {
+    #[default]
+    Game,
+    Console,
+    Chat,
 }
 
+// Buttons held down by the "+" commands, such as "+forward", and let go by their "-" counterparts.
+pub const BUTTONS: [&str; 18] = [
+    "forward",
+    "back",
+    "moveleft",
+    "moveright",
+    "left",
+    "right",
+    "lookup",
+    "lookdown",
+    "moveup",
+    "movedown",
+    "speed",
+    "strafe",
+    "attack",
+    "jump",
+    "use",
+    "klook",
+    "mlook",
+    "showscores",
+];
+
+// Pitch of the view is kept between looking this far up and down, in degrees.
+const MAX_PITCH_UP: f32 = -70.0;
+const MAX_PITCH_DOWN: f32 = 80.0;
+
 #[derive(Debug, Default)]
 pub struct Input {
     bindings: HashMap<String, String>,
+    focus: InputFocus,
+    // Chat message being typed, and whether it only goes to the team.
+    chat: String,
+    chat_team: bool,
+    keys_down: HashSet<&'static str>,
+    // Keys holding each button down, a button is only let go once all of them are released.
+    buttons: HashMap<String, HashSet<String>>,
+    // Mouse movement since the last frame, and the one of the frame before it for filtering.
+    mouse_motion: [f32; 2],
+    previous_mouse_motion: [f32; 2],
+    // Pitch and yaw of the view in degrees, turned by the mouse.
+    view_angles: [f32; 2],
 }
 
 impl Input {
@@ -43,22 +96,188 @@ impl Input {
         self.bindings.clear();
     }
 
-    pub fn handle_input_event(&self, input_event: InputEvent) -> Option<&String> {
+    pub fn bindings(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.bindings
+            .iter()
+            .map(|(key, action)| (key.as_str(), action.as_str()))
+    }
+
+    #[must_use]
+    pub fn binding_for(&self, key: &str) -> Option<&str> {
+        self.bindings
+            .get(key.trim().to_lowercase().as_str())
+            .map(String::as_str)
+    }
+
+    // Lines listing the bindings in key name order.
+    fn list_bindings(&self) -> Vec<String> {
+        let mut bindings: Vec<_> = self.bindings().collect();
+        bindings.sort_unstable();
+
+        let mut lines: Vec<String> = bindings
+            .iter()
+            .map(|(key, action)| format!("\"{key}\" \"{action}\""))
+            .collect();
+        lines.push(format!("{} bindings", bindings.len()));
+
+        lines
+    }
+
+    #[must_use]
+    pub const fn focus(&self) -> InputFocus {
+        self.focus
+    }
+
+    // Moving the focus drops the chat message being typed.
+    pub fn set_focus(&mut self, focus: InputFocus) {
+        self.focus = focus;
+        self.chat.clear();
+    }
+
+    // Chat message being typed and whether it only goes to the team.
+    #[must_use]
+    pub fn chat_line(&self) -> Option<(&str, bool)> {
+        (self.focus == InputFocus::Chat).then_some((self.chat.as_str(), self.chat_team))
+    }
+
+    // Edits the chat message with a key press.  Enter sends it and returns the command doing so, Escape drops it.
+    fn edit_chat_line(&mut self, code: KeyCode, text: Option<char>) -> Option<String> {
+        match code {
+            KeyCode::Enter | KeyCode::NumpadEnter => {
+                let cmd = if self.chat_team { "say_team" } else { "say" };
+                // Double quotes would end the argument early.
+                let message = self.chat.replace('"', "'");
+                self.set_focus(InputFocus::Game);
+                return (!message.trim().is_empty()).then(|| format!("{cmd} \"{message}\""));
+            }
+            KeyCode::Escape => self.set_focus(InputFocus::Game),
+            KeyCode::Backspace => {
+                self.chat.pop();
+            }
+            _ => {
+                if let Some(c) = text.filter(|c| !c.is_control()) {
+                    self.chat.push(c);
+                }
+            }
+        }
+
+        None
+    }
+
+    #[must_use]
+    pub fn is_down(&self, key: &str) -> bool {
+        self.keys_down.contains(key)
+    }
+
+    // Buttons are looked at every frame, so that "+forward" keeps moving for as long as it is held.
+    #[must_use]
+    pub fn is_button_down(&self, button: &str) -> bool {
+        self.buttons
+            .get(button)
+            .is_some_and(|keys| !keys.is_empty())
+    }
+
+    fn press_button(&mut self, button: &str, key: Option<&String>) {
+        self.buttons
+            .entry(button.to_owned())
+            .or_default()
+            .insert(key.cloned().unwrap_or_default());
+    }
+
+    // Without a key, as typed in the console, the button is let go whatever holds it.
+    fn release_button(&mut self, button: &str, key: Option<&String>) {
+        if let Some(keys) = self.buttons.get_mut(button) {
+            if let Some(key) = key {
+                keys.remove(key);
+            } else {
+                keys.clear();
+            }
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn add_mouse_motion(&mut self, dx: f64, dy: f64) {
+        self.mouse_motion[0] += dx as f32;
+        self.mouse_motion[1] += dy as f32;
+    }
+
+    #[must_use]
+    pub const fn view_angles(&self) -> [f32; 2] {
+        self.view_angles
+    }
+
+    // Turns the view by the mouse movement of the frame the way the original engine does, moving the mouse forward
+    // looks up.
+    fn mouse_look(&mut self, console: &Console) {
+        let var = |name, default| console.get_var::<f32>(name).unwrap_or(default);
+
+        let motion = std::mem::take(&mut self.mouse_motion);
+        let [dx, dy] = if console
+            .get_var::<i32>("m_filter")
+            .is_some_and(|filter| filter != 0)
+        {
+            let previous = self.previous_mouse_motion;
+            [
+                f32::midpoint(motion[0], previous[0]),
+                f32::midpoint(motion[1], previous[1]),
+            ]
+        } else {
+            motion
+        };
+        self.previous_mouse_motion = motion;
+
+        let sensitivity = var("sensitivity", 3.0);
+        let [pitch, yaw] = &mut self.view_angles;
+        *pitch = (var("m_pitch", 0.022) * sensitivity)
+            .mul_add(dy, *pitch)
+            .clamp(MAX_PITCH_UP, MAX_PITCH_DOWN);
+        *yaw = (-var("m_yaw", 0.022) * sensitivity)
+            .mul_add(dx, *yaw)
+            .rem_euclid(360.0);
+    }
+
+    // Returns the commands bound to the key of the event.  The mouse wheel has no release, a wheel step presses and
+    // releases its key at once.
+    pub fn handle_input_event(&mut self, input_event: InputEvent) -> Vec<String> {
         match input_event {
-            InputEvent::KeyboardInput {
-                code,
-                state: ElementState::Pressed,
-            } => Self::from_key_code(code),
-            InputEvent::MouseInput {
-                button,
-                state: ElementState::Pressed,
-            } => Self::from_mouse_button(button),
-            InputEvent::MouseWheel { delta } => Self::from_mouse_scroll_delta(delta),
-            _ => None,
+            InputEvent::KeyboardInput { code, state, .. } => Self::from_key_code(code)
+                .and_then(|key| self.handle_key(key, state.is_pressed()))
+                .into_iter()
+                .collect(),
+            InputEvent::MouseInput { button, state } => Self::from_mouse_button(button)
+                .and_then(|key| self.handle_key(key, state.is_pressed()))
+                .into_iter()
+                .collect(),
+            InputEvent::MouseWheel { delta } => Self::from_mouse_scroll_delta(delta)
+                .map(|key| [self.handle_key(key, true), self.handle_key(key, false)])
+                .into_iter()
+                .flatten()
+                .flatten()
+                .collect(),
+            InputEvent::Text { .. } => Vec::new(),
+        }
+    }
+
+    // Keys bound to a button command, such as "+forward", run it when pressed and its "-" counterpart when released,
+    // with the key as argument so that several keys can hold the same button.  Other bindings only run when the key
+    // is pressed, and neither runs again on auto-repeat.
+    fn handle_key(&mut self, key: &'static str, pressed: bool) -> Option<String> {
+        let changed = if pressed {
+            self.keys_down.insert(key)
+        } else {
+            self.keys_down.remove(key)
+        };
+        if !changed {
+            return None;
+        }
+
+        let action = self.bindings.get(key)?;
+        match action.strip_prefix('+') {
+            Some(_) if pressed => Some(format!("{action} \"{key}\"")),
+            Some(button) => Some(format!("-{button} \"{key}\"")),
+            None if pressed => Some(action.clone()),
+            None => None,
         }
-        .map_or(None, |key| {
-            self.bindings.get(key.trim().to_lowercase().as_str())
-        })
     }
 
     fn execute_command(&mut self, command: &ConsoleCmd) {
@@ -75,10 +294,28 @@ impl Input {
             [ref cmd] if cmd == "unbindall" => {
                 self.unbind_all();
             }
+            // Starts typing a chat message to everybody, or only to the team with messagemode2.
+            [ref cmd] if cmd == "messagemode" || cmd == "messagemode2" => {
+                self.set_focus(InputFocus::Chat);
+                self.chat_team = cmd == "messagemode2";
+            }
+            // Holds a button down, the key holding it is given when the command comes from a binding.
+            [ref cmd, key @ ..] if key.len() <= 1 && Self::is_button(cmd, '+') => {
+                self.press_button(&cmd[1..], key.first());
+            }
+            // Lets a button go.
+            [ref cmd, key @ ..] if key.len() <= 1 && Self::is_button(cmd, '-') => {
+                self.release_button(&cmd[1..], key.first());
+            }
             _ => (),
         }
     }
 
+    fn is_button(cmd: &str, prefix: char) -> bool {
+        cmd.strip_prefix(prefix)
+            .is_some_and(|button| BUTTONS.contains(&button))
+    }
+
     fn from_key_code(key_code: KeyCode) -> Option<&'static str> {
         match key_code {
             KeyCode::Backquote => Some("`"),
@@ -170,25 +407,55 @@ impl Input {
             MouseButton::Left => Some("mouse1"),
             MouseButton::Right => Some("mouse2"),
             MouseButton::Middle => Some("mouse3"),
-            _ => None,
+            MouseButton::Back => Some("mouse4"),
+            MouseButton::Forward => Some("mouse5"),
+            MouseButton::Other(_) => None,
         }
     }
 
+    // Only vertical scrolling has keys.
     fn from_mouse_scroll_delta(mouse_wheel: MouseScrollDelta) -> Option<&'static str> {
-        match mouse_wheel {
-            MouseScrollDelta::LineDelta(_, y) => {
-                if y > 0.0 {
-                    Some("mwheelup")
-                } else {
-                    Some("mwheeldown")
-                }
-            }
-            MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => {
-                if y > 0.0 {
-                    Some("mwheelup")
-                } else {
-                    Some("mwheeldown")
-                }
+        let y = match mouse_wheel {
+            MouseScrollDelta::LineDelta(_, y) => f64::from(y),
+            MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => y,
+        };
+        if y > 0.0 {
+            Some("mwheelup")
+        } else if y < 0.0 {
+            Some("mwheeldown")
+        } else {
+            None
+        }
+    }
+}
+
+// Lines scrolled by the page keys and the mouse wheel while the console is open.
+const CONSOLE_PAGE_LINES: usize = 4;
+
+// Edits the console line with a key press, the keys go to the console instead of their bindings while it is open.
+fn edit_console_line(
+    console_input: &mut ConsoleInput,
+    console: &mut Console,
+    code: KeyCode,
+    text: Option<char>,
+) {
+    match code {
+        KeyCode::Enter | KeyCode::NumpadEnter => console_input.submit(console),
+        KeyCode::Tab => console_input.complete(console),
+        KeyCode::Backspace => console_input.backspace(),
+        KeyCode::Delete => console_input.delete(),
+        KeyCode::ArrowLeft => console_input.move_left(),
+        KeyCode::ArrowRight => console_input.move_right(),
+        KeyCode::Home => console_input.home(),
+        KeyCode::End => console_input.end(),
+        KeyCode::ArrowUp => console_input.history_up(),
+        KeyCode::ArrowDown => console_input.history_down(),
+        KeyCode::PageUp => console_input.scroll_up(CONSOLE_PAGE_LINES, console),
+        KeyCode::PageDown => console_input.scroll_down(CONSOLE_PAGE_LINES),
+        KeyCode::Escape => console.push_command("toggleconsole"),
+        _ => {
+            if let Some(c) = text.filter(|c| !c.is_control()) {
+                console_input.insert(c);
             }
         }
     }
@@ -196,20 +463,95 @@ impl Input {
 
 #[system]
 pub fn input_handler(
-    #[resource] input_event: &Option<InputEvent>,
-    #[resource] input: &Input,
+    #[resource] input_events: &Vec<InputEvent>,
+    #[resource] input: &mut Input,
     #[resource] console: &mut Console,
+    #[resource] console_input: &mut ConsoleInput,
 ) {
-    if let Some(input_event) = input_event {
-        input
-            .handle_input_event(*input_event)
-            .map(|action| console.push_command(&action));
+    for input_event in input_events {
+        match (input.focus(), *input_event) {
+            // The console key can't be bound to anything else, otherwise the console could be locked out.
+            (
+                _,
+                InputEvent::KeyboardInput {
+                    code: KeyCode::Backquote,
+                    state: ElementState::Pressed,
+                    ..
+                },
+            ) => console.push_command("toggleconsole"),
+            (
+                InputFocus::Console,
+                InputEvent::KeyboardInput {
+                    code,
+                    state: ElementState::Pressed,
+                    text,
+                },
+            ) => edit_console_line(console_input, console, code, text),
+            (InputFocus::Console, InputEvent::MouseWheel { delta }) => {
+                match Input::from_mouse_scroll_delta(delta) {
+                    Some("mwheelup") => console_input.scroll_up(1, console),
+                    Some(_) => console_input.scroll_down(1),
+                    None => (),
+                }
+            }
+            (InputFocus::Console, InputEvent::Text { text }) => console_input.insert(text),
+            (
+                InputFocus::Chat,
+                InputEvent::KeyboardInput {
+                    code,
+                    state: ElementState::Pressed,
+                    text,
+                },
+            ) => {
+                if let Some(command) = input.edit_chat_line(code, text) {
+                    console.push_command(&command);
+                }
+            }
+            (InputFocus::Chat, InputEvent::Text { text }) => input.chat.push(text),
+            // Releases still reach the bindings while typing, so that no button is left held down.
+            (
+                InputFocus::Console | InputFocus::Chat,
+                InputEvent::MouseInput {
+                    state: ElementState::Pressed,
+                    ..
+                }
+                | InputEvent::MouseWheel { .. },
+            ) => (),
+            (_, input_event) => {
+                for action in input.handle_input_event(input_event) {
+                    console.push_command(&action);
+                }
+            }
+        }
     }
 }
 
 #[system]
 pub fn input_command_executor(#[resource] input: &mut Input, #[resource] console: &mut Console) {
-    console
-        .commands()
-        .for_each(|command| input.execute_command(command));
+    let mut lines = Vec::new();
+    console.commands().for_each(|command| match &command[..] {
+        // Lists every key binding.
+        [ref cmd] if cmd == "bindlist" => lines.extend(input.list_bindings()),
+        // Shows the commands bound to a key.
+        [ref cmd, key] if cmd == "bind" => lines.push(input.binding_for(key).map_or_else(
+            || format!("\"{key}\" is not bound"),
+            |action| format!("\"{key}\" = \"{action}\""),
+        )),
+        _ => input.execute_command(command),
+    });
+
+    for line in &lines {
+        console.print(line);
+    }
+}
+
+#[system]
+pub fn input_mouse_look(#[resource] input: &mut Input, #[resource] console: &Console) {
+    // The mouse only turns the view while the console is closed.
+    if input.focus() == InputFocus::Console {
+        input.mouse_motion = [0.0; 2];
+        input.previous_mouse_motion = [0.0; 2];
+    } else {
+        input.mouse_look(console);
+    }
 }
@@ -0,0 +1,155 @@
+use std::{
+    fmt::Write as _,
+    io::{ErrorKind, Write},
+    net::{Ipv4Addr, TcpListener},
+    time::Duration,
+};
+
+use legion::system;
+
+use crate::{
+    audio::Audio,
+    client::Connection,
+    console::Console,
+    graphics::Graphics,
+    time::{RenderTime, WorldTime},
+};
+
+// Time given to a tool to take the snapshot before the connection is dropped, so that a stuck reader can't hold up a
+// frame for long.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Local endpoint for external tools.  Every connection gets a JSON snapshot of the client state and is closed.
+#[derive(Default)]
+pub struct Inspector {
+    listener: Option<TcpListener>,
+}
+
+impl Inspector {
+    // Listens on the loopback interface only, port 0 closes the endpoint.
+    pub fn listen(&mut self, port: u16) -> anyhow::Result<()> {
+        self.listener = None;
+        if port != 0 {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+            listener.set_nonblocking(true)?;
+            self.listener = Some(listener);
+        }
+
+        Ok(())
+    }
+
+    // Sends the snapshot to every tool waiting for one, it is only taken when somebody asks for it.
+    fn serve<F: Fn() -> String>(&self, snapshot: F) {
+        let Some(listener) = &self.listener else {
+            return;
+        };
+
+        let mut taken = None;
+        loop {
+            match listener.accept() {
+                Ok((mut stream, address)) => {
+                    let snapshot = taken.get_or_insert_with(&snapshot);
+                    if let Err(e) = stream
+                        .set_nonblocking(false)
+                        .and_then(|()| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                        .and_then(|()| stream.write_all(snapshot.as_bytes()))
+                    {
+                        tracing::warn!("couldn't send snapshot to {}: {}", address, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    tracing::warn!("inspection endpoint failed: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn snapshot(
+    console: &Console,
+    connection: &Connection,
+    render_time: &RenderTime,
+    world_time: &WorldTime,
+    audio: &Audio,
+    graphics: &Graphics,
+) -> String {
+    let mut variables: Vec<_> = console.variables().collect();
+    variables.sort_unstable_by_key(|(name, _)| *name);
+    let cvars = variables
+        .iter()
+        .map(|(name, cvar)| format!("{}:{}", json_string(name), json_string(cvar.value())))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let (pictures, texture_bytes) = graphics.texture_usage();
+    format!(
+        "{{\"cvars\":{{{}}},\"connection\":{},\"time\":{{\"render\":{},\"world\":{},\"paused\":{}}},\
+         \"perf\":{{\"frame_time\":{},\"sound_channels\":{},\"pictures\":{},\"texture_bytes\":{}}}}}\n",
+        cvars,
+        json_string(&format!("{:?}", connection.state())),
+        render_time.elapsed(),
+        world_time.time(),
+        world_time.is_paused(),
+        render_time.delta(),
+        audio.busy_channels(),
+        pictures,
+        texture_bytes,
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", u32::from(c));
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+
+    json
+}
+
+#[system]
+pub fn inspector_update(
+    #[resource] inspector: &mut Inspector,
+    #[resource] console: &Console,
+    #[resource] connection: &Connection,
+    #[resource] render_time: &RenderTime,
+    #[resource] world_time: &WorldTime,
+    #[resource] audio: &Audio,
+    #[resource] graphics: &Graphics,
+) {
+    if console
+        .changed_vars()
+        .any(|name| name == "host_inspectport")
+    {
+        let port = console.get_var::<u16>("host_inspectport").unwrap_or(0);
+        if let Err(e) = inspector.listen(port) {
+            tracing::warn!(
+                "couldn't open inspection endpoint on port {}: {:#}",
+                port,
+                e
+            );
+        }
+    }
+
+    inspector.serve(|| {
+        snapshot(
+            console,
+            connection,
+            render_time,
+            world_time,
+            audio,
+            graphics,
+        )
+    });
+}
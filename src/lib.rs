@@ -5,126 +5,648 @@ use std::{
     fs::File,
     io::{BufReader, Cursor, Read, Seek},
     path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
 };
 
 use anyhow::bail;
+use audio::{Audio, SoundRegistry};
 use byteorder::LittleEndian;
+use console::Console;
+use graphics::Graphics;
 use indexmap::IndexMap;
+use legion::system;
+use screen::Loading;
 
 pub mod app;
 pub mod audio;
+pub mod client;
 pub mod console;
+pub mod fuzz;
 pub mod graphics;
+pub mod hud;
 pub mod input;
+pub mod inspect;
 pub mod message;
+pub mod picture;
+pub mod prelude;
+pub mod sbar;
+pub mod screen;
+pub mod time;
+pub mod window;
 
 pub trait ReadSeek: Read + Seek + Send + Sync {}
 
 impl<R: Read + Seek + Send + Sync> ReadSeek for R {}
 
+pub trait Resource: Sized + Send + 'static {
+    fn load(reader: Box<dyn ReadSeek>) -> anyhow::Result<Self>;
+}
+
+impl Resource for Vec<u8> {
+    fn load(mut reader: Box<dyn ReadSeek>) -> anyhow::Result<Self> {
+        let mut buf = Self::new();
+        reader.read_to_end(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
+impl Resource for String {
+    fn load(mut reader: Box<dyn ReadSeek>) -> anyhow::Result<Self> {
+        let mut buf = Self::new();
+        reader.read_to_string(&mut buf)?;
+
+        Ok(buf)
+    }
+}
+
 pub struct ResourceFiles {
     dir_path: PathBuf,
-    packs: Box<[Pack<BufReader<File>>]>,
+    pack_paths: Box<[PathBuf]>,
+    packs: Arc<[Mutex<Pack<PackFile>>]>,
+    loader: ResourceLoader,
 }
 
 impl ResourceFiles {
     pub fn new<P: AsRef<Path>>(dir_path: P) -> anyhow::Result<Self> {
         let pattern = format!("{}/**/*.pak", dir_path.as_ref().display());
-        let packs = glob::glob(pattern.as_str())?
+        let pack_paths: Box<[PathBuf]> = glob::glob(pattern.as_str())?
             .filter_map(Result::ok)
+            .collect();
+        let packs = pack_paths
+            .iter()
             .map(|file_path| {
-                let file = File::open(&file_path)?;
-                let file_reader = BufReader::new(file);
+                let file = File::open(file_path)?;
+                let file_reader = PackFile::open(file)?;
                 let pack = Pack::new(file_reader)?;
 
-                Ok(pack)
+                Ok(Mutex::new(pack))
             })
             .collect::<anyhow::Result<Vec<_>>>()?
-            .into_boxed_slice();
+            .into();
+
+        let worker_count = thread::available_parallelism().map_or(1, usize::from);
 
         Ok(Self {
             dir_path: dir_path.as_ref().to_path_buf(),
+            pack_paths,
             packs,
+            loader: ResourceLoader::new(worker_count),
         })
     }
 
-    pub fn take<P: AsRef<Path>>(&mut self, file_path: P) -> anyhow::Result<Box<dyn ReadSeek>> {
-        let full_path = self.dir_path.join(file_path.as_ref());
+    #[must_use]
+    pub fn dir_path(&self) -> &Path {
+        &self.dir_path
+    }
+
+    // Places searched for a file, from the one that wins to the one that loses: the loose files of the game directory,
+    // then the packs with the last one in name order first.
+    #[must_use]
+    pub fn search_path(&self) -> Vec<&Path> {
+        std::iter::once(self.dir_path.as_path())
+            .chain(self.pack_paths.iter().rev().map(PathBuf::as_path))
+            .collect()
+    }
+
+    // Returns the place the file is taken from, if any.
+    pub fn which<P: AsRef<Path>>(&self, file_path: P) -> Option<&Path> {
+        let file_path = file_path.as_ref();
+        if self.dir_path.join(file_path).is_file() {
+            return Some(&self.dir_path);
+        }
+
+        let file_name = file_path.to_str()?;
+        self.packs
+            .iter()
+            .zip(self.pack_paths.iter())
+            .rev()
+            .find(|(pack, _)| pack.lock().unwrap().file_names().any(|e| e == file_name))
+            .map(|(_, pack_path)| pack_path.as_path())
+    }
+
+    // Number of files in the pack directories and an estimate of the memory those directories take.
+    #[must_use]
+    pub fn pack_usage(&self) -> (usize, usize) {
+        self.packs.iter().fold((0, 0), |(count, bytes), pack| {
+            let pack = pack.lock().unwrap();
+            let names: usize = pack.file_names().map(str::len).sum();
+            let entries = pack.files.len();
+            drop(pack);
+            (
+                count + entries,
+                bytes + names + entries * std::mem::size_of::<(String, (u64, u64))>(),
+            )
+        })
+    }
+
+    pub fn take<P: AsRef<Path>>(&self, file_path: P) -> anyhow::Result<Box<dyn ReadSeek>> {
+        Self::take_from(&self.dir_path, &self.packs, file_path.as_ref())
+    }
+
+    // Queues the file to be read and parsed by the worker pool.  The returned future is resolved once a worker is done
+    // with it, and the file path is reported by `poll_completed` at the same time.
+    pub fn load_async<T, P>(&self, file_path: P) -> ResourceFuture<T>
+    where
+        T: Resource,
+        P: AsRef<Path>,
+    {
+        self.load_async_with(file_path, T::load)
+    }
+
+    // Same as `load_async`, with the file parsed by the given function instead of the `Resource` implementation.
+    pub fn load_async_with<T, P, F>(&self, file_path: P, parse: F) -> ResourceFuture<T>
+    where
+        T: Send + 'static,
+        P: AsRef<Path>,
+        F: FnOnce(Box<dyn ReadSeek>) -> anyhow::Result<T> + Send + 'static,
+    {
+        let dir_path = self.dir_path.clone();
+        let packs = Arc::clone(&self.packs);
+        let file_path = file_path.as_ref().to_path_buf();
+
+        let (result_sender, result_receiver) = mpsc::channel();
+        let completed_sender = self.loader.completed_sender.clone();
+        self.loader.submit(Box::new(move || {
+            let result = Self::take_from(&dir_path, &packs, &file_path).and_then(parse);
+            // The future may have been dropped already, in which case nobody is waiting for the result.
+            let _ = result_sender.send(result);
+            let _ = completed_sender.send(file_path);
+        }));
+
+        ResourceFuture {
+            receiver: Mutex::new(result_receiver),
+            result: None,
+        }
+    }
+
+    // Drains the paths of the asynchronous loads finished since the last call.
+    pub fn poll_completed(&self) -> Vec<PathBuf> {
+        self.loader
+            .completed_receiver
+            .lock()
+            .unwrap()
+            .try_iter()
+            .collect()
+    }
+
+    // Starts loading every file of the bundle in the background, duplicated paths are only loaded once.
+    pub fn load_bundle<P, I>(&self, name: &str, file_paths: I) -> ResourceBundle
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = P>,
+    {
+        self.load_bundle_with(name, file_paths, Vec::<u8>::load)
+    }
+
+    // Same as `load_bundle`, with every file parsed by the given function.
+    pub fn load_bundle_with<T, P, I, F>(
+        &self,
+        name: &str,
+        file_paths: I,
+        parse: F,
+    ) -> ResourceBundle<T>
+    where
+        T: Send + 'static,
+        P: AsRef<Path>,
+        I: IntoIterator<Item = P>,
+        F: Fn(Box<dyn ReadSeek>) -> anyhow::Result<T> + Clone + Send + 'static,
+    {
+        let mut pending = IndexMap::new();
+        for file_path in file_paths {
+            let file_path = file_path.as_ref().to_path_buf();
+            if !pending.contains_key(&file_path) {
+                let future = self.load_async_with(&file_path, parse.clone());
+                pending.insert(file_path, future);
+            }
+        }
+
+        ResourceBundle {
+            name: name.to_owned(),
+            total: pending.len(),
+            pending,
+            loaded: IndexMap::new(),
+            failed: IndexMap::new(),
+        }
+    }
+
+    fn take_from(
+        dir_path: &Path,
+        packs: &[Mutex<Pack<PackFile>>],
+        file_path: &Path,
+    ) -> anyhow::Result<Box<dyn ReadSeek>> {
+        let full_path = dir_path.join(file_path);
         if full_path.is_file() {
             let mut file = File::open(full_path)?;
             let mut buf = Vec::new();
             file.read_to_end(&mut buf)?;
 
             return Ok(Box::new(Cursor::new(buf)));
-        } else {
-            let file_name = file_path.as_ref().to_str().unwrap();
-            for pack in self.packs.iter_mut().rev() {
-                if pack.file_names().any(|e| e == file_name) {
-                    return pack.take(file_name);
-                }
+        }
+
+        let file_name = file_path.to_str().unwrap();
+        for pack in packs.iter().rev() {
+            let mut pack = pack.lock().unwrap();
+            if pack.file_names().any(|e| e == file_name) {
+                return pack.take(file_name);
             }
-            bail!("file not found: {}", file_name)
         }
+        bail!("file not found: {}", file_name)
+    }
+}
+
+// Drains the asynchronous loads finished during the frame, so that their paths don't pile up for the whole session.
+#[system]
+pub fn resource_update(#[resource] resource_files: &ResourceFiles) {
+    for file_path in resource_files.poll_completed() {
+        tracing::debug!("loaded {}", file_path.display());
+    }
+}
+
+#[system]
+pub fn meminfo_command_executor(
+    #[resource] resource_files: &ResourceFiles,
+    #[resource] graphics: &Graphics,
+    #[resource] audio: &Audio,
+    #[resource] sound_registry: &SoundRegistry,
+    #[resource] loading: &Option<Loading>,
+    #[resource] console: &mut Console,
+) {
+    // Reports an estimate of the memory used by each subsystem.
+    if console.commands().any(|command| command[0] == "meminfo") {
+        let (pack_files, pack_bytes) = resource_files.pack_usage();
+        console.print(&format!(
+            "packs: {} files, {} KiB",
+            pack_files,
+            pack_bytes / 1024
+        ));
+
+        let bundle_bytes = loading.as_ref().map_or(0, Loading::loaded_bytes);
+        console.print(&format!("loading: {} KiB", bundle_bytes / 1024));
+
+        let (pictures, texture_bytes) = graphics.texture_usage();
+        console.print(&format!(
+            "textures: {} pictures, {} KiB",
+            pictures,
+            texture_bytes / 1024
+        ));
+
+        console.print(&format!(
+            "sound: {} channels playing, {} sounds precached, {} KiB",
+            audio.busy_channels(),
+            sound_registry.len(),
+            sound_registry.loaded_bytes() / 1024
+        ));
+        match audio.pcm_cache().disk_usage() {
+            Ok(bytes) => console.print(&format!("sound cache: {} KiB on disk", bytes / 1024)),
+            Err(e) => console.print_warning(&format!("couldn't read the sound cache: {e:#}")),
+        }
+    }
+}
+
+#[system]
+pub fn resource_command_executor(
+    #[resource] resource_files: &ResourceFiles,
+    #[resource] console: &mut Console,
+) {
+    let lines: Vec<String> = console
+        .commands()
+        .flat_map(|command| match &command[..] {
+            // Shows the search order of the game files.
+            [ref cmd] if cmd == "path" => resource_files
+                .search_path()
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+            // Shows where a file is taken from.
+            [ref cmd, file_path] if cmd == "which" => {
+                vec![resource_files.which(file_path).map_or_else(
+                    || format!("{file_path} not found"),
+                    |path| format!("{} is taken from {}", file_path, path.display()),
+                )]
+            }
+            _ => Vec::new(),
+        })
+        .collect();
+
+    for line in &lines {
+        console.print(line);
     }
 }
 
-struct Pack<R> {
+// A named set of files loaded together, such as everything a level needs before it can start.
+pub struct ResourceBundle<T = Vec<u8>> {
+    name: String,
+    total: usize,
+    pending: IndexMap<PathBuf, ResourceFuture<T>>,
+    loaded: IndexMap<PathBuf, T>,
+    failed: IndexMap<PathBuf, anyhow::Error>,
+}
+
+impl<T> ResourceBundle<T> {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Collects the files finished since the last call.
+    pub fn poll(&mut self) {
+        self.pending
+            .retain(|file_path, future| match future.try_take() {
+                Some(Ok(buf)) => {
+                    self.loaded.insert(file_path.clone(), buf);
+                    false
+                }
+                Some(Err(e)) => {
+                    self.failed.insert(file_path.clone(), e);
+                    false
+                }
+                None => true,
+            });
+    }
+
+    // Fraction of the files already finished, either loaded or failed.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn progress(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+
+        (self.total - self.pending.len()) as f32 / self.total as f32
+    }
+
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn take<P: AsRef<Path>>(&mut self, file_path: P) -> Option<T> {
+        self.loaded.shift_remove(file_path.as_ref())
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = (&Path, &anyhow::Error)> {
+        self.failed
+            .iter()
+            .map(|(file_path, e)| (file_path.as_path(), e))
+    }
+}
+
+impl ResourceBundle {
+    // Bytes of the files loaded and not taken yet.
+    #[must_use]
+    pub fn loaded_bytes(&self) -> usize {
+        self.loaded.values().map(Vec::len).sum()
+    }
+}
+
+pub struct ResourceFuture<T> {
+    receiver: Mutex<Receiver<anyhow::Result<T>>>,
+    result: Option<anyhow::Result<T>>,
+}
+
+impl<T> ResourceFuture<T> {
+    pub fn is_ready(&mut self) -> bool {
+        if self.result.is_none() {
+            self.result = match self.receiver.get_mut().unwrap().try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => {
+                    Some(Err(anyhow::anyhow!("resource loader stopped")))
+                }
+            };
+        }
+
+        self.result.is_some()
+    }
+
+    // Returns the loaded resource without blocking, or `None` if the load is still in flight.
+    pub fn try_take(&mut self) -> Option<anyhow::Result<T>> {
+        if self.is_ready() {
+            self.result.take()
+        } else {
+            None
+        }
+    }
+
+    // Blocks the calling thread until the load is done.
+    pub fn wait(self) -> anyhow::Result<T> {
+        match self.result {
+            Some(result) => result,
+            None => self
+                .receiver
+                .into_inner()
+                .unwrap()
+                .recv()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("resource loader stopped"))),
+        }
+    }
+}
+
+type ResourceJob = Box<dyn FnOnce() + Send>;
+
+struct ResourceLoader {
+    job_sender: Option<Sender<ResourceJob>>,
+    workers: Vec<JoinHandle<()>>,
+    completed_sender: Sender<PathBuf>,
+    completed_receiver: Mutex<Receiver<PathBuf>>,
+}
+
+impl ResourceLoader {
+    fn new(worker_count: usize) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<ResourceJob>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..worker_count)
+            .map(|_| {
+                let job_receiver = Arc::clone(&job_receiver);
+                thread::spawn(move || loop {
+                    // The lock guard is dropped before running the job so the other workers can pick up jobs meanwhile.
+                    let job = job_receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        let (completed_sender, completed_receiver) = mpsc::channel();
+
+        Self {
+            job_sender: Some(job_sender),
+            workers,
+            completed_sender,
+            completed_receiver: Mutex::new(completed_receiver),
+        }
+    }
+
+    fn submit(&self, job: ResourceJob) {
+        if let Some(job_sender) = &self.job_sender {
+            job_sender.send(job).unwrap();
+        }
+    }
+}
+
+impl Drop for ResourceLoader {
+    fn drop(&mut self) {
+        // Closing the job channel makes every worker leave its loop once the pending jobs are done.
+        self.job_sender.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(not(feature = "mmap"))]
+type PackFile = BufReader<File>;
+
+#[cfg(feature = "mmap")]
+type PackFile = Cursor<MappedFile>;
+
+trait PackReader: ReadSeek {
+    fn open(file: File) -> anyhow::Result<Self>
+    where
+        Self: Sized;
+
+    fn slice(&mut self, offset: u64, length: u64) -> anyhow::Result<Box<dyn ReadSeek>> {
+        self.seek(std::io::SeekFrom::Start(offset))?;
+
+        // The buffer grows with the data actually read, a bogus length cannot allocate more than the pack holds.
+        let mut buf = Vec::new();
+        (&mut *self).take(length).read_to_end(&mut buf)?;
+        if buf.len() as u64 != length {
+            bail!("file entry out of bounds");
+        }
+
+        Ok(Box::new(Cursor::new(buf)))
+    }
+}
+
+impl PackReader for BufReader<File> {
+    fn open(file: File) -> anyhow::Result<Self> {
+        Ok(Self::new(file))
+    }
+}
+
+impl PackReader for Cursor<Vec<u8>> {
+    fn open(mut file: File) -> anyhow::Result<Self> {
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        Ok(Self::new(buf))
+    }
+}
+
+// A read-only memory map of a whole pack file, shared by every slice handed out from it.
+#[cfg(feature = "mmap")]
+#[derive(Clone)]
+struct MappedFile {
+    mmap: Arc<memmap2::Mmap>,
+    range: std::ops::Range<usize>,
+}
+
+#[cfg(feature = "mmap")]
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap[self.range.clone()]
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl PackReader for Cursor<MappedFile> {
+    fn open(file: File) -> anyhow::Result<Self> {
+        // SAFETY: pack files are only ever read, the engine never writes to them while they are mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        let range = 0..mmap.len();
+
+        Ok(Self::new(MappedFile {
+            mmap: Arc::new(mmap),
+            range,
+        }))
+    }
+
+    fn slice(&mut self, offset: u64, length: u64) -> anyhow::Result<Box<dyn ReadSeek>> {
+        let start = usize::try_from(offset)?;
+        let end = start.checked_add(usize::try_from(length)?);
+        let Some(end) = end.filter(|end| *end <= self.get_ref().range.len()) else {
+            bail!("file entry out of bounds");
+        };
+
+        Ok(Box::new(Self::new(MappedFile {
+            mmap: Arc::clone(&self.get_ref().mmap),
+            range: start..end,
+        })))
+    }
+}
+
+pub(crate) struct Pack<R> {
     reader: R,
     files: IndexMap<String, (u64, u64)>,
 }
 
 impl<R> Pack<R>
 where
-    R: ReadSeek,
+    R: PackReader,
 {
-    fn new(mut reader: R) -> anyhow::Result<Self> {
+    pub(crate) fn new(mut reader: R) -> anyhow::Result<Self> {
+        use byteorder::ReadBytesExt;
+
         let mut ident = [0u8; 4];
         reader.read_exact(&mut ident)?;
         if &ident != b"PACK" {
             bail!("invalid signature");
         }
 
-        use byteorder::ReadBytesExt;
-        let dir_offset = reader.read_i32::<LittleEndian>()?;
-        let dir_length = reader.read_i32::<LittleEndian>()?;
+        let dir_offset = reader.read_u32::<LittleEndian>()?;
+        let dir_length = reader.read_u32::<LittleEndian>()?;
 
-        reader.seek(std::io::SeekFrom::Start(dir_offset as u64))?;
+        // Every entry is checked against the size of the pack, so that a damaged directory is rejected here rather
+        // than when one of its files is taken.
+        let pack_length = reader.seek(std::io::SeekFrom::End(0))?;
+        if u64::from(dir_offset) + u64::from(dir_length) > pack_length {
+            bail!("directory out of bounds");
+        }
+        reader.seek(std::io::SeekFrom::Start(u64::from(dir_offset)))?;
 
         let file_count = dir_length / 64;
-        let mut files = IndexMap::with_capacity(file_count as usize);
+        let mut files = IndexMap::new();
 
         for _ in 0..file_count {
             let mut buf = [0u8; 56];
             reader.read_exact(&mut buf)?;
 
-            // Convert buffer to string and trim null bytes
-            let file_name = match str::from_utf8(&buf) {
-                Ok(name) => name.trim_end_matches('\0').to_string(),
-                Err(_) => bail!("invalid UTF-8 file name"),
+            // Names are padded with null bytes, and whatever follows the first one is garbage.
+            let name_length = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+            let Ok(file_name) = str::from_utf8(&buf[..name_length]) else {
+                bail!("invalid UTF-8 file name");
             };
 
             let file_offset = reader.read_u32::<LittleEndian>()?;
             let file_length = reader.read_u32::<LittleEndian>()?;
-            files.insert(file_name.into(), (file_offset as u64, file_length as u64));
+            if u64::from(file_offset) + u64::from(file_length) > pack_length {
+                bail!("file entry out of bounds: {}", file_name);
+            }
+            files.insert(
+                file_name.to_owned(),
+                (u64::from(file_offset), u64::from(file_length)),
+            );
         }
 
         Ok(Self { reader, files })
     }
 
-    fn file_names(&self) -> impl Iterator<Item = &str> {
-        self.files.keys().map(|s| s.as_ref())
+    pub(crate) fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(String::as_str)
     }
 
-    fn take(&mut self, name: &str) -> anyhow::Result<Box<dyn ReadSeek>> {
+    pub(crate) fn take(&mut self, name: &str) -> anyhow::Result<Box<dyn ReadSeek>> {
         match self.files.get(name) {
-            Some((file_offset, file_length)) => {
-                self.reader.seek(std::io::SeekFrom::Start(*file_offset))?;
-
-                let mut buf = vec![0; *file_length as usize];
-                self.reader.read_exact(&mut buf)?;
-
-                Ok(Box::new(Cursor::new(buf)))
-            }
+            Some((file_offset, file_length)) => self.reader.slice(*file_offset, *file_length),
             None => bail!("file not found: {}", name),
         }
     }
@@ -1,3 +1,7 @@
+use quake_rs::{
+    app::{command_line, HeadlessApp},
+    prelude::GameApp,
+};
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 fn main() {
@@ -5,5 +9,17 @@ fn main() {
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
-    quake_rs::app::GameApp::default().run_app().unwrap();
+    // "--headless" runs the client without a window wherever it is given, the other arguments are console commands
+    // such as "+map e1m1".
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let arg_count = args.len();
+    args.retain(|arg| arg != "--headless");
+    let headless = args.len() != arg_count;
+
+    let commands = command_line(args);
+    if headless {
+        HeadlessApp::new(&commands).unwrap().run_app().unwrap();
+    } else {
+        GameApp::new(&commands).run_app().unwrap();
+    }
 }
@@ -1,12 +1,26 @@
-use std::{collections::VecDeque, io::SeekFrom};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Cursor, ErrorKind, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use anyhow::bail;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use legion::system;
 use num_derive::{FromPrimitive, ToPrimitive};
-use num_traits::FromPrimitive;
+use num_traits::{FromPrimitive, ToPrimitive};
 
-use crate::{console::Console, ReadSeek, ResourceFiles};
+use crate::{
+    audio::SoundRegistry,
+    client::Connection,
+    console::{Console, ConsoleCmd},
+    hud::Hud,
+    screen::Loading,
+    time::WorldTime,
+    ReadSeek, ResourceFiles,
+};
 
 #[derive(Clone, Debug)]
 pub enum Message {
@@ -53,9 +67,282 @@ pub enum ServerMessageId {
     Finale = 0x1f,
     CdTrack = 0x20,
     SellScreen = 0x21,
+    CutScene = 0x22,
 }
 
-#[derive(Clone, Debug)]
+// Entity update messages have the high bit of the message id set, the low bits are the first byte of the update mask.
+const UPDATE_ENTITY_FLAG: u8 = 0x80;
+
+// Bits of the sound message mask.
+pub const SND_VOLUME: u8 = 0x01;
+pub const SND_ATTENUATION: u8 = 0x02;
+
+// Bits of the player data message mask.
+pub const SU_VIEWHEIGHT: u16 = 0x0001;
+pub const SU_IDEALPITCH: u16 = 0x0002;
+pub const SU_PUNCH1: u16 = 0x0004;
+pub const SU_VELOCITY1: u16 = 0x0020;
+pub const SU_ITEMS: u16 = 0x0200;
+pub const SU_ONGROUND: u16 = 0x0400;
+pub const SU_INWATER: u16 = 0x0800;
+pub const SU_WEAPONFRAME: u16 = 0x1000;
+pub const SU_ARMOR: u16 = 0x2000;
+pub const SU_WEAPON: u16 = 0x4000;
+
+// Bits of the entity update message mask.
+pub const U_MOREBITS: u16 = 0x0001;
+pub const U_ORIGIN1: u16 = 0x0002;
+pub const U_ORIGIN2: u16 = 0x0004;
+pub const U_ORIGIN3: u16 = 0x0008;
+pub const U_ANGLE2: u16 = 0x0010;
+pub const U_NOLERP: u16 = 0x0020;
+pub const U_FRAME: u16 = 0x0040;
+pub const U_ANGLE1: u16 = 0x0100;
+pub const U_ANGLE3: u16 = 0x0200;
+pub const U_MODEL: u16 = 0x0400;
+pub const U_COLORMAP: u16 = 0x0800;
+pub const U_SKIN: u16 = 0x1000;
+pub const U_EFFECTS: u16 = 0x2000;
+pub const U_LONGENTITY: u16 = 0x4000;
+
+#[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
+pub enum TempEntityId {
+    Spike = 0,
+    SuperSpike = 1,
+    Gunshot = 2,
+    Explosion = 3,
+    TarExplosion = 4,
+    Lightning1 = 5,
+    Lightning2 = 6,
+    WizSpike = 7,
+    KnightSpike = 8,
+    Lightning3 = 9,
+    LavaSplash = 10,
+    Teleport = 11,
+    Explosion2 = 12,
+    Beam = 13,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TempEntity {
+    // A spike hitting a wall.
+    Spike {
+        origin: [f32; 3],
+    },
+    // A super spike hitting a wall.
+    SuperSpike {
+        origin: [f32; 3],
+    },
+    // A bullet hitting a wall.
+    Gunshot {
+        origin: [f32; 3],
+    },
+    // A rocket or grenade exploding.
+    Explosion {
+        origin: [f32; 3],
+    },
+    // The explosion of a spawn.
+    TarExplosion {
+        origin: [f32; 3],
+    },
+    // The lightning bolts of a shambler.
+    Lightning1 {
+        entity: u16,
+        start: [f32; 3],
+        end: [f32; 3],
+    },
+    // The lightning bolt of the thunderbolt weapon.
+    Lightning2 {
+        entity: u16,
+        start: [f32; 3],
+        end: [f32; 3],
+    },
+    // A scrag spit hitting a wall.
+    WizSpike {
+        origin: [f32; 3],
+    },
+    // A death knight spike hitting a wall.
+    KnightSpike {
+        origin: [f32; 3],
+    },
+    // The lightning bolt of Chthon.
+    Lightning3 {
+        entity: u16,
+        start: [f32; 3],
+        end: [f32; 3],
+    },
+    // The lava splash of Chthon rising from the lava.
+    LavaSplash {
+        origin: [f32; 3],
+    },
+    // The particle sphere of a teleporter.
+    Teleport {
+        origin: [f32; 3],
+    },
+    // An explosion with colored particles.
+    Explosion2 {
+        origin: [f32; 3],
+        color_start: u8,
+        color_length: u8,
+    },
+    // The grappling hook beam.
+    Beam {
+        entity: u16,
+        start: [f32; 3],
+        end: [f32; 3],
+    },
+}
+
+impl TempEntity {
+    fn deserialize<R>(reader: &mut R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let code = reader.read_u8()?;
+        let Some(entity_id) = TempEntityId::from_u8(code) else {
+            bail!("invalid temporary entity id: {}", code);
+        };
+
+        let entity = match entity_id {
+            TempEntityId::Spike => Self::Spike {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::SuperSpike => Self::SuperSpike {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Gunshot => Self::Gunshot {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Explosion => Self::Explosion {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::TarExplosion => Self::TarExplosion {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Lightning1 => Self::Lightning1 {
+                entity: reader.read_u16::<LittleEndian>()?,
+                start: read_coords(reader)?,
+                end: read_coords(reader)?,
+            },
+            TempEntityId::Lightning2 => Self::Lightning2 {
+                entity: reader.read_u16::<LittleEndian>()?,
+                start: read_coords(reader)?,
+                end: read_coords(reader)?,
+            },
+            TempEntityId::WizSpike => Self::WizSpike {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::KnightSpike => Self::KnightSpike {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Lightning3 => Self::Lightning3 {
+                entity: reader.read_u16::<LittleEndian>()?,
+                start: read_coords(reader)?,
+                end: read_coords(reader)?,
+            },
+            TempEntityId::LavaSplash => Self::LavaSplash {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Teleport => Self::Teleport {
+                origin: read_coords(reader)?,
+            },
+            TempEntityId::Explosion2 => Self::Explosion2 {
+                origin: read_coords(reader)?,
+                color_start: reader.read_u8()?,
+                color_length: reader.read_u8()?,
+            },
+            TempEntityId::Beam => Self::Beam {
+                entity: reader.read_u16::<LittleEndian>()?,
+                start: read_coords(reader)?,
+                end: read_coords(reader)?,
+            },
+        };
+
+        Ok(entity)
+    }
+
+    fn serialize<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Self::Spike { origin } => {
+                writer.write_u8(TempEntityId::Spike as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::SuperSpike { origin } => {
+                writer.write_u8(TempEntityId::SuperSpike as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Gunshot { origin } => {
+                writer.write_u8(TempEntityId::Gunshot as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Explosion { origin } => {
+                writer.write_u8(TempEntityId::Explosion as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::TarExplosion { origin } => {
+                writer.write_u8(TempEntityId::TarExplosion as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Lightning1 { entity, start, end } => {
+                writer.write_u8(TempEntityId::Lightning1 as u8)?;
+                writer.write_u16::<LittleEndian>(*entity)?;
+                write_coords(writer, *start)?;
+                write_coords(writer, *end)?;
+            }
+            Self::Lightning2 { entity, start, end } => {
+                writer.write_u8(TempEntityId::Lightning2 as u8)?;
+                writer.write_u16::<LittleEndian>(*entity)?;
+                write_coords(writer, *start)?;
+                write_coords(writer, *end)?;
+            }
+            Self::WizSpike { origin } => {
+                writer.write_u8(TempEntityId::WizSpike as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::KnightSpike { origin } => {
+                writer.write_u8(TempEntityId::KnightSpike as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Lightning3 { entity, start, end } => {
+                writer.write_u8(TempEntityId::Lightning3 as u8)?;
+                writer.write_u16::<LittleEndian>(*entity)?;
+                write_coords(writer, *start)?;
+                write_coords(writer, *end)?;
+            }
+            Self::LavaSplash { origin } => {
+                writer.write_u8(TempEntityId::LavaSplash as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Teleport { origin } => {
+                writer.write_u8(TempEntityId::Teleport as u8)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Explosion2 {
+                origin,
+                color_start,
+                color_length,
+            } => {
+                writer.write_u8(TempEntityId::Explosion2 as u8)?;
+                write_coords(writer, *origin)?;
+                writer.write_u8(*color_start)?;
+                writer.write_u8(*color_length)?;
+            }
+            Self::Beam { entity, start, end } => {
+                writer.write_u8(TempEntityId::Beam as u8)?;
+                writer.write_u16::<LittleEndian>(*entity)?;
+                write_coords(writer, *start)?;
+                write_coords(writer, *end)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ServerMessage {
     // Something is bad. This message should never appear.
     Bad,
@@ -67,7 +354,6 @@ pub enum ServerMessage {
     UpdateStat {
         index: u8,
         value: i32,
-        state: [i32; 32],
     },
     // The version of the server.
     Version {
@@ -75,14 +361,15 @@ pub enum ServerMessage {
     },
     // Sets the camera position to the origin of this entity.
     SetView {
-        entity: i16,
+        entity: u16,
     },
-    // This message starts the play of a sound at a specific point.
+    // This message starts the play of a sound at a specific point.  The volume and attenuation are only meaningful
+    // when their bit is set in the mask, otherwise the defaults apply.
     Sound {
-        mask: i32,
+        mask: u8,
         volume: u8,
         attenuation: f32,
-        channel: i8,
+        channel: u8,
         entity_id: u16,
         sound_id: u8,
         origin: [f32; 3],
@@ -93,11 +380,11 @@ pub enum ServerMessage {
     },
     // The client prints the text in the top left corner of the screen. The text appears on the console as well.
     Print {
-        text: &'static str,
+        text: String,
     },
     // The client transfers the text to the console and runs it.
     StuffText {
-        text: &'static str,
+        text: String,
     },
     // This message set the camera orientation.
     SetAngle {
@@ -105,124 +392,114 @@ pub enum ServerMessage {
     },
     // This message is usually one of the first messages after a level start. It loads model and sound files.
     ServerInfo {
-        serverversion: u32,
-        maxclients: u32,
-        multi: u32,
-        mapname: &'static str,
-        precache_models: Box<[&'static str]>,
-        nummodels: u32,
-        precache_sounds: Box<[&'static str]>,
-        numsounds: u32,
+        serverversion: i32,
+        maxclients: u8,
+        multi: u8,
+        mapname: String,
+        precache_models: Box<[String]>,
+        precache_sounds: Box<[String]>,
     },
     // This message defines a light style.
     LightStyle {
-        style: u32,
-        lightmap: &'static str,
+        style: u8,
+        lightmap: String,
     },
     // This message sets the player name.
     UpdateName {
-        player: u32,
-        netname: &'static str,
+        player: u8,
+        netname: String,
     },
     // This message updates the frag count of a specific player.
     UpdateFrags {
         player: u8,
-        frags: u16,
+        frags: i16,
     },
-    // This message updates the status line and the camera coordinates.
+    // This message updates the status line and the camera coordinates.  The optional fields are only meaningful when
+    // their bit is set in the mask, otherwise the defaults apply.
     PlayerData {
-        mask: u32,
+        mask: u16,
         view_ofs_z: f32,
         ang_ofs_1: f32,
         angles: [f32; 3],
         vel: [f32; 3],
         items: u32,
-        weaponframe: u32,
-        armorvalue: u32,
-        weaponmodel: u32,
-        health: u32,
-        currentammo: u32,
-        ammo_shells: u32,
-        ammo_nails: u32,
-        ammo_rockets: u32,
-        ammo_cells: u32,
-        weapon: u32,
+        weaponframe: u8,
+        armorvalue: u8,
+        weaponmodel: u8,
+        health: i16,
+        currentammo: u8,
+        ammo_shells: u8,
+        ammo_nails: u8,
+        ammo_rockets: u8,
+        ammo_cells: u8,
+        weapon: u8,
     },
     // Stops a sound.
     StopSound {
-        uk_short: u32,
+        entity_id: u16,
+        channel: u8,
     },
-    // Updates the colors of the specified player.
+    // Updates the colors of the specified player.  The high nibble is the shirt color and the low one the pants color.
     UpdateColors {
-        player: u32,
-        colors: u32,
-        shirtcolor: u32,
-        pantscolor: u32,
+        player: u8,
+        colors: u8,
     },
     // This starts particles flying around. This happens, if a barrel explodes or blood particles fly after being hit by an axe, shells or nails.
     Particle {
         origin: [f32; 3],
         vel: [f32; 3],
-        color: u32,
-        count: u32,
+        count: u8,
+        color: u8,
     },
     // Tells how severe was a hit and from which points it came.
     Damage {
-        save: u32,
-        take: u32,
+        save: u8,
+        take: u8,
         origin: [f32; 3],
     },
     // This message creates a static entity and sets the internal default values.
     SpawnStatic {
-        static_entitycount: u32,
-        default_modelindex: u32,
-        default_frame: u32,
-        default_colormap: u32,
-        default_skin: u32,
+        default_modelindex: u8,
+        default_frame: u8,
+        default_colormap: u8,
+        default_skin: u8,
         default_origin: [f32; 3],
         default_angles: [f32; 3],
     },
     // This message creates a dynamic entity and sets the internal default values.
     SpawnBaseline {
-        entity: u32,
-        default_modelindex: u32,
-        default_frame: u32,
-        default_colormap: u32,
-        default_skin: u32,
+        entity: u16,
+        default_modelindex: u8,
+        default_frame: u8,
+        default_colormap: u8,
+        default_skin: u8,
         default_origin: [f32; 3],
         default_angles: [f32; 3],
     },
     // This message creates a temporary entity.
     TempEntity {
-        entitytype: u32,
-        entity: u32,
-        origin: [f32; 3],
-        trace_endpos: [f32; 3],
+        entity: TempEntity,
     },
     // Set the pause state. The time stands still but all entities get their update messages.
     SetPause {
-        pausestate: u32,
+        pausestate: u8,
     },
     // This message selects the client state.
     SignOnStage {
-        signon: u32,
+        signon: u8,
     },
     // Prints the specified text at the center of the screen.
     CenterPrint {
-        text: &'static str,
+        text: String,
     },
     // This message indicates the death of a monster.
-    KilledMonster {
-        killed_monsters: u32,
-    },
+    KilledMonster,
     // This message receives a client, if the player enters a secret area. It comes usually with a print message.
-    FoundSecret {
-        found_secrets: u32,
-    },
+    FoundSecret,
     // This message starts a static (ambient) sound not connected to an entity but to a position.
     SpawnStaticSound {
         origin: [f32; 3],
-        soundnum: u32,
+        soundnum: u8,
         vol: f32,
         attenuation: f32,
     },
@@ -230,86 +507,860 @@ pub enum ServerMessage {
     Intermission,
     // Displays the episode end screen and some text.
     Finale {
-        text: &'static str,
+        text: String,
     },
     // This message selects the audio CD track numbers.
     CdTrack {
-        fromtrack: u32,
-        totrack: u32,
+        fromtrack: u8,
+        totrack: u8,
     },
     // Displays the help and sell screen.
     SellScreen,
+    // Displays the text at the center of the screen while a cutscene is played.
+    CutScene {
+        text: String,
+    },
     // This is the general entity update message. For every entity (potentially) in sight the server sends such a message. The message contains only the values, which changed since
-    // the creation (or spawning) of the entity (with spawnstatic, spawnbaseline).
+    // the creation (or spawning) of the entity (with spawnstatic, spawnbaseline).  The fields are only meaningful when
+    // their bit is set in the mask, otherwise the baseline values apply.
     Updateentity {
-        mask: u32,
-        entity: u32,
-        modelindex: u32,
-        frame: u32,
-        colormap: u32,
-        skin: u32,
-        attack_state: u32,
+        mask: u16,
+        entity: u16,
+        modelindex: u8,
+        frame: u8,
+        colormap: u8,
+        skin: u8,
+        effects: u8,
         origin: [f32; 3],
         angles: [f32; 3],
-        new: u32,
+    },
+    // A message id this client does not know about.  Its length is unknown, so the rest of the block is skipped.
+    UnknownOpcode {
+        code: u8,
     },
 }
 
 impl ServerMessage {
-    fn deserialize<R>(reader: &mut R) -> anyhow::Result<ServerMessage>
+    #[allow(clippy::too_many_lines)]
+    fn deserialize<R>(reader: &mut R) -> anyhow::Result<Self>
     where
         R: ReadSeek,
     {
         let code = reader.read_u8()?;
-        let msg_id = match ServerMessageId::from_u8(code) {
-            Some(msg_id) => msg_id,
-            None => bail!("invalid message id: {}", code),
+        if code & UPDATE_ENTITY_FLAG != 0 {
+            return Self::deserialize_update_entity(code, reader);
+        }
+
+        let Some(msg_id) = ServerMessageId::from_u8(code) else {
+            return Ok(Self::UnknownOpcode { code });
         };
 
         let msg = match msg_id {
-            ServerMessageId::Bad => ServerMessage::Bad,
-            ServerMessageId::Nop => ServerMessage::Nop,
-            ServerMessageId::Disconnect => ServerMessage::Disconnect,
-            ServerMessageId::UpdateStat => todo!(),
-            ServerMessageId::Version => {
-                let version = reader.read_i32::<LittleEndian>()?;
-                ServerMessage::Version { version }
-            }
-            ServerMessageId::SetView => todo!(),
-            ServerMessageId::Sound => todo!(),
-            ServerMessageId::Time => {
-                let time = reader.read_f32::<LittleEndian>()?;
-                ServerMessage::Time { time }
-            }
-            ServerMessageId::Print => todo!(),
-            ServerMessageId::StuffText => todo!(),
-            ServerMessageId::SetAngle => todo!(),
-            ServerMessageId::ServerInfo => todo!(),
-            ServerMessageId::LightStyle => todo!(),
-            ServerMessageId::UpdateName => todo!(),
-            ServerMessageId::UpdateFrags => todo!(),
-            ServerMessageId::PlayerData => todo!(),
-            ServerMessageId::StopSound => todo!(),
-            ServerMessageId::UpdateColors => todo!(),
-            ServerMessageId::Particle => todo!(),
-            ServerMessageId::Damage => todo!(),
-            ServerMessageId::SpawnStatic => todo!(),
-            ServerMessageId::SpawnBaseline => todo!(),
-            ServerMessageId::TempEntity => todo!(),
-            ServerMessageId::SetPause => todo!(),
-            ServerMessageId::SignOnStage => todo!(),
-            ServerMessageId::CenterPrint => todo!(),
-            ServerMessageId::KilledMonster => todo!(),
-            ServerMessageId::FoundSecret => todo!(),
-            ServerMessageId::SpawnStaticSound => todo!(),
-            ServerMessageId::Intermission => todo!(),
-            ServerMessageId::Finale => todo!(),
-            ServerMessageId::CdTrack => todo!(),
-            ServerMessageId::SellScreen => todo!(),
+            ServerMessageId::Bad => Self::Bad,
+            ServerMessageId::Nop => Self::Nop,
+            ServerMessageId::Disconnect => Self::Disconnect,
+            ServerMessageId::UpdateStat => Self::UpdateStat {
+                index: reader.read_u8()?,
+                value: reader.read_i32::<LittleEndian>()?,
+            },
+            ServerMessageId::Version => Self::Version {
+                version: reader.read_i32::<LittleEndian>()?,
+            },
+            ServerMessageId::SetView => Self::SetView {
+                entity: reader.read_u16::<LittleEndian>()?,
+            },
+            ServerMessageId::Sound => {
+                let mask = reader.read_u8()?;
+                let volume = if mask & SND_VOLUME != 0 {
+                    reader.read_u8()?
+                } else {
+                    255
+                };
+                let attenuation = if mask & SND_ATTENUATION != 0 {
+                    f32::from(reader.read_u8()?) / 64.0
+                } else {
+                    1.0
+                };
+                let channel = reader.read_u16::<LittleEndian>()?;
+                let sound_id = reader.read_u8()?;
+                let origin = read_coords(reader)?;
+
+                Self::Sound {
+                    mask,
+                    volume,
+                    attenuation,
+                    channel: (channel & 0x07).to_le_bytes()[0],
+                    entity_id: channel >> 3,
+                    sound_id,
+                    origin,
+                }
+            }
+            ServerMessageId::Time => Self::Time {
+                time: reader.read_f32::<LittleEndian>()?,
+            },
+            ServerMessageId::Print => Self::Print {
+                text: read_string(reader)?,
+            },
+            ServerMessageId::StuffText => Self::StuffText {
+                text: read_string(reader)?,
+            },
+            ServerMessageId::SetAngle => Self::SetAngle {
+                angles: read_angles(reader)?,
+            },
+            ServerMessageId::ServerInfo => {
+                let serverversion = reader.read_i32::<LittleEndian>()?;
+                let maxclients = reader.read_u8()?;
+                let multi = reader.read_u8()?;
+                let mapname = read_string(reader)?;
+                let precache_models = read_string_list(reader)?;
+                let precache_sounds = read_string_list(reader)?;
+
+                Self::ServerInfo {
+                    serverversion,
+                    maxclients,
+                    multi,
+                    mapname,
+                    precache_models,
+                    precache_sounds,
+                }
+            }
+            ServerMessageId::LightStyle => Self::LightStyle {
+                style: reader.read_u8()?,
+                lightmap: read_string(reader)?,
+            },
+            ServerMessageId::UpdateName => Self::UpdateName {
+                player: reader.read_u8()?,
+                netname: read_string(reader)?,
+            },
+            ServerMessageId::UpdateFrags => Self::UpdateFrags {
+                player: reader.read_u8()?,
+                frags: reader.read_i16::<LittleEndian>()?,
+            },
+            ServerMessageId::PlayerData => Self::deserialize_player_data(reader)?,
+            ServerMessageId::StopSound => {
+                let channel = reader.read_u16::<LittleEndian>()?;
+
+                Self::StopSound {
+                    entity_id: channel >> 3,
+                    channel: (channel & 0x07).to_le_bytes()[0],
+                }
+            }
+            ServerMessageId::UpdateColors => Self::UpdateColors {
+                player: reader.read_u8()?,
+                colors: reader.read_u8()?,
+            },
+            ServerMessageId::Particle => Self::Particle {
+                origin: read_coords(reader)?,
+                vel: [
+                    f32::from(reader.read_i8()?) / 16.0,
+                    f32::from(reader.read_i8()?) / 16.0,
+                    f32::from(reader.read_i8()?) / 16.0,
+                ],
+                count: reader.read_u8()?,
+                color: reader.read_u8()?,
+            },
+            ServerMessageId::Damage => Self::Damage {
+                save: reader.read_u8()?,
+                take: reader.read_u8()?,
+                origin: read_coords(reader)?,
+            },
+            ServerMessageId::SpawnStatic => {
+                let default_modelindex = reader.read_u8()?;
+                let default_frame = reader.read_u8()?;
+                let default_colormap = reader.read_u8()?;
+                let default_skin = reader.read_u8()?;
+                let (default_origin, default_angles) = read_coords_and_angles(reader)?;
+
+                Self::SpawnStatic {
+                    default_modelindex,
+                    default_frame,
+                    default_colormap,
+                    default_skin,
+                    default_origin,
+                    default_angles,
+                }
+            }
+            ServerMessageId::SpawnBaseline => {
+                let entity = reader.read_u16::<LittleEndian>()?;
+                let default_modelindex = reader.read_u8()?;
+                let default_frame = reader.read_u8()?;
+                let default_colormap = reader.read_u8()?;
+                let default_skin = reader.read_u8()?;
+                let (default_origin, default_angles) = read_coords_and_angles(reader)?;
+
+                Self::SpawnBaseline {
+                    entity,
+                    default_modelindex,
+                    default_frame,
+                    default_colormap,
+                    default_skin,
+                    default_origin,
+                    default_angles,
+                }
+            }
+            ServerMessageId::TempEntity => Self::TempEntity {
+                entity: TempEntity::deserialize(reader)?,
+            },
+            ServerMessageId::SetPause => Self::SetPause {
+                pausestate: reader.read_u8()?,
+            },
+            ServerMessageId::SignOnStage => Self::SignOnStage {
+                signon: reader.read_u8()?,
+            },
+            ServerMessageId::CenterPrint => Self::CenterPrint {
+                text: read_string(reader)?,
+            },
+            ServerMessageId::KilledMonster => Self::KilledMonster,
+            ServerMessageId::FoundSecret => Self::FoundSecret,
+            ServerMessageId::SpawnStaticSound => Self::SpawnStaticSound {
+                origin: read_coords(reader)?,
+                soundnum: reader.read_u8()?,
+                vol: f32::from(reader.read_u8()?) / 255.0,
+                attenuation: f32::from(reader.read_u8()?) / 64.0,
+            },
+            ServerMessageId::Intermission => Self::Intermission,
+            ServerMessageId::Finale => Self::Finale {
+                text: read_string(reader)?,
+            },
+            ServerMessageId::CdTrack => Self::CdTrack {
+                fromtrack: reader.read_u8()?,
+                totrack: reader.read_u8()?,
+            },
+            ServerMessageId::SellScreen => Self::SellScreen,
+            ServerMessageId::CutScene => Self::CutScene {
+                text: read_string(reader)?,
+            },
         };
 
         Ok(msg)
     }
+
+    fn deserialize_player_data<R>(reader: &mut R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let mask = reader.read_u16::<LittleEndian>()?;
+
+        let view_ofs_z = if mask & SU_VIEWHEIGHT != 0 {
+            f32::from(reader.read_i8()?)
+        } else {
+            22.0
+        };
+        let ang_ofs_1 = if mask & SU_IDEALPITCH != 0 {
+            f32::from(reader.read_i8()?)
+        } else {
+            0.0
+        };
+
+        let mut angles = [0.0; 3];
+        let mut vel = [0.0; 3];
+        for i in 0..3 {
+            if mask & (SU_PUNCH1 << i) != 0 {
+                angles[i] = f32::from(reader.read_i8()?);
+            }
+            if mask & (SU_VELOCITY1 << i) != 0 {
+                vel[i] = f32::from(reader.read_i8()?) * 16.0;
+            }
+        }
+
+        // The items are always sent even when the bit is not set in the mask.
+        let items = reader.read_u32::<LittleEndian>()?;
+
+        let weaponframe = if mask & SU_WEAPONFRAME != 0 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+        let armorvalue = if mask & SU_ARMOR != 0 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+        let weaponmodel = if mask & SU_WEAPON != 0 {
+            reader.read_u8()?
+        } else {
+            0
+        };
+
+        Ok(Self::PlayerData {
+            mask,
+            view_ofs_z,
+            ang_ofs_1,
+            angles,
+            vel,
+            items,
+            weaponframe,
+            armorvalue,
+            weaponmodel,
+            health: reader.read_i16::<LittleEndian>()?,
+            currentammo: reader.read_u8()?,
+            ammo_shells: reader.read_u8()?,
+            ammo_nails: reader.read_u8()?,
+            ammo_rockets: reader.read_u8()?,
+            ammo_cells: reader.read_u8()?,
+            weapon: reader.read_u8()?,
+        })
+    }
+
+    fn deserialize_update_entity<R>(code: u8, reader: &mut R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let mut mask = u16::from(code & !UPDATE_ENTITY_FLAG);
+        if mask & U_MOREBITS != 0 {
+            mask |= u16::from(reader.read_u8()?) << 8;
+        }
+
+        let entity = if mask & U_LONGENTITY != 0 {
+            reader.read_u16::<LittleEndian>()?
+        } else {
+            u16::from(reader.read_u8()?)
+        };
+
+        let mut read_byte = |bit: u16| -> anyhow::Result<u8> {
+            Ok(if mask & bit != 0 {
+                reader.read_u8()?
+            } else {
+                0
+            })
+        };
+        let modelindex = read_byte(U_MODEL)?;
+        let frame = read_byte(U_FRAME)?;
+        let colormap = read_byte(U_COLORMAP)?;
+        let skin = read_byte(U_SKIN)?;
+        let effects = read_byte(U_EFFECTS)?;
+
+        let mut origin = [0.0; 3];
+        let mut angles = [0.0; 3];
+        for (i, (origin_bit, angle_bit)) in [
+            (U_ORIGIN1, U_ANGLE1),
+            (U_ORIGIN2, U_ANGLE2),
+            (U_ORIGIN3, U_ANGLE3),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if mask & origin_bit != 0 {
+                origin[i] = read_coord(reader)?;
+            }
+            if mask & angle_bit != 0 {
+                angles[i] = read_angle(reader)?;
+            }
+        }
+
+        Ok(Self::Updateentity {
+            mask,
+            entity,
+            modelindex,
+            frame,
+            colormap,
+            skin,
+            effects,
+            origin,
+            angles,
+        })
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn serialize<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let msg_id = match self {
+            Self::Bad => ServerMessageId::Bad,
+            Self::Nop => ServerMessageId::Nop,
+            Self::Disconnect => ServerMessageId::Disconnect,
+            Self::UpdateStat { .. } => ServerMessageId::UpdateStat,
+            Self::Version { .. } => ServerMessageId::Version,
+            Self::SetView { .. } => ServerMessageId::SetView,
+            Self::Sound { .. } => ServerMessageId::Sound,
+            Self::Time { .. } => ServerMessageId::Time,
+            Self::Print { .. } => ServerMessageId::Print,
+            Self::StuffText { .. } => ServerMessageId::StuffText,
+            Self::SetAngle { .. } => ServerMessageId::SetAngle,
+            Self::ServerInfo { .. } => ServerMessageId::ServerInfo,
+            Self::LightStyle { .. } => ServerMessageId::LightStyle,
+            Self::UpdateName { .. } => ServerMessageId::UpdateName,
+            Self::UpdateFrags { .. } => ServerMessageId::UpdateFrags,
+            Self::PlayerData { .. } => ServerMessageId::PlayerData,
+            Self::StopSound { .. } => ServerMessageId::StopSound,
+            Self::UpdateColors { .. } => ServerMessageId::UpdateColors,
+            Self::Particle { .. } => ServerMessageId::Particle,
+            Self::Damage { .. } => ServerMessageId::Damage,
+            Self::SpawnStatic { .. } => ServerMessageId::SpawnStatic,
+            Self::SpawnBaseline { .. } => ServerMessageId::SpawnBaseline,
+            Self::TempEntity { .. } => ServerMessageId::TempEntity,
+            Self::SetPause { .. } => ServerMessageId::SetPause,
+            Self::SignOnStage { .. } => ServerMessageId::SignOnStage,
+            Self::CenterPrint { .. } => ServerMessageId::CenterPrint,
+            Self::KilledMonster => ServerMessageId::KilledMonster,
+            Self::FoundSecret => ServerMessageId::FoundSecret,
+            Self::SpawnStaticSound { .. } => ServerMessageId::SpawnStaticSound,
+            Self::Intermission => ServerMessageId::Intermission,
+            Self::Finale { .. } => ServerMessageId::Finale,
+            Self::CdTrack { .. } => ServerMessageId::CdTrack,
+            Self::SellScreen => ServerMessageId::SellScreen,
+            Self::CutScene { .. } => ServerMessageId::CutScene,
+            Self::Updateentity { .. } => return self.serialize_update_entity(writer),
+            Self::UnknownOpcode { code } => {
+                bail!("unknown message id can not be serialized: {}", code)
+            }
+        };
+        writer.write_u8(msg_id.to_u8().unwrap())?;
+
+        match self {
+            Self::Bad
+            | Self::Nop
+            | Self::Disconnect
+            | Self::KilledMonster
+            | Self::FoundSecret
+            | Self::Intermission
+            | Self::SellScreen
+            | Self::Updateentity { .. }
+            | Self::UnknownOpcode { .. } => (),
+            Self::UpdateStat { index, value } => {
+                writer.write_u8(*index)?;
+                writer.write_i32::<LittleEndian>(*value)?;
+            }
+            Self::Version { version } => writer.write_i32::<LittleEndian>(*version)?,
+            Self::SetView { entity } => writer.write_u16::<LittleEndian>(*entity)?,
+            Self::Sound {
+                mask,
+                volume,
+                attenuation,
+                channel,
+                entity_id,
+                sound_id,
+                origin,
+            } => {
+                writer.write_u8(*mask)?;
+                if mask & SND_VOLUME != 0 {
+                    writer.write_u8(*volume)?;
+                }
+                if mask & SND_ATTENUATION != 0 {
+                    writer.write_u8(to_byte(attenuation * 64.0))?;
+                }
+                writer.write_u16::<LittleEndian>((entity_id << 3) | u16::from(channel & 0x07))?;
+                writer.write_u8(*sound_id)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::Time { time } => writer.write_f32::<LittleEndian>(*time)?,
+            Self::Print { text }
+            | Self::StuffText { text }
+            | Self::CenterPrint { text }
+            | Self::Finale { text }
+            | Self::CutScene { text } => write_string(writer, text)?,
+            Self::SetAngle { angles } => write_angles(writer, *angles)?,
+            Self::ServerInfo {
+                serverversion,
+                maxclients,
+                multi,
+                mapname,
+                precache_models,
+                precache_sounds,
+            } => {
+                writer.write_i32::<LittleEndian>(*serverversion)?;
+                writer.write_u8(*maxclients)?;
+                writer.write_u8(*multi)?;
+                write_string(writer, mapname)?;
+                write_string_list(writer, precache_models)?;
+                write_string_list(writer, precache_sounds)?;
+            }
+            Self::LightStyle { style, lightmap } => {
+                writer.write_u8(*style)?;
+                write_string(writer, lightmap)?;
+            }
+            Self::UpdateName { player, netname } => {
+                writer.write_u8(*player)?;
+                write_string(writer, netname)?;
+            }
+            Self::UpdateFrags { player, frags } => {
+                writer.write_u8(*player)?;
+                writer.write_i16::<LittleEndian>(*frags)?;
+            }
+            Self::PlayerData { .. } => self.serialize_player_data(writer)?,
+            Self::StopSound { entity_id, channel } => {
+                writer.write_u16::<LittleEndian>((entity_id << 3) | u16::from(channel & 0x07))?;
+            }
+            Self::UpdateColors { player, colors } => {
+                writer.write_u8(*player)?;
+                writer.write_u8(*colors)?;
+            }
+            Self::Particle {
+                origin,
+                vel,
+                count,
+                color,
+            } => {
+                write_coords(writer, *origin)?;
+                for v in vel {
+                    writer.write_i8(to_char(v * 16.0))?;
+                }
+                writer.write_u8(*count)?;
+                writer.write_u8(*color)?;
+            }
+            Self::Damage { save, take, origin } => {
+                writer.write_u8(*save)?;
+                writer.write_u8(*take)?;
+                write_coords(writer, *origin)?;
+            }
+            Self::SpawnStatic {
+                default_modelindex,
+                default_frame,
+                default_colormap,
+                default_skin,
+                default_origin,
+                default_angles,
+            } => {
+                writer.write_u8(*default_modelindex)?;
+                writer.write_u8(*default_frame)?;
+                writer.write_u8(*default_colormap)?;
+                writer.write_u8(*default_skin)?;
+                write_coords_and_angles(writer, *default_origin, *default_angles)?;
+            }
+            Self::SpawnBaseline {
+                entity,
+                default_modelindex,
+                default_frame,
+                default_colormap,
+                default_skin,
+                default_origin,
+                default_angles,
+            } => {
+                writer.write_u16::<LittleEndian>(*entity)?;
+                writer.write_u8(*default_modelindex)?;
+                writer.write_u8(*default_frame)?;
+                writer.write_u8(*default_colormap)?;
+                writer.write_u8(*default_skin)?;
+                write_coords_and_angles(writer, *default_origin, *default_angles)?;
+            }
+            Self::TempEntity { entity } => entity.serialize(writer)?,
+            Self::SetPause { pausestate } => writer.write_u8(*pausestate)?,
+            Self::SignOnStage { signon } => writer.write_u8(*signon)?,
+            Self::SpawnStaticSound {
+                origin,
+                soundnum,
+                vol,
+                attenuation,
+            } => {
+                write_coords(writer, *origin)?;
+                writer.write_u8(*soundnum)?;
+                writer.write_u8(to_byte(vol * 255.0))?;
+                writer.write_u8(to_byte(attenuation * 64.0))?;
+            }
+            Self::CdTrack { fromtrack, totrack } => {
+                writer.write_u8(*fromtrack)?;
+                writer.write_u8(*totrack)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn serialize_player_data<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let Self::PlayerData {
+            mask,
+            view_ofs_z,
+            ang_ofs_1,
+            angles,
+            vel,
+            items,
+            weaponframe,
+            armorvalue,
+            weaponmodel,
+            health,
+            currentammo,
+            ammo_shells,
+            ammo_nails,
+            ammo_rockets,
+            ammo_cells,
+            weapon,
+        } = self
+        else {
+            bail!("not a player data message");
+        };
+
+        writer.write_u16::<LittleEndian>(*mask)?;
+        if mask & SU_VIEWHEIGHT != 0 {
+            writer.write_i8(to_char(*view_ofs_z))?;
+        }
+        if mask & SU_IDEALPITCH != 0 {
+            writer.write_i8(to_char(*ang_ofs_1))?;
+        }
+        for i in 0..3 {
+            if mask & (SU_PUNCH1 << i) != 0 {
+                writer.write_i8(to_char(angles[i]))?;
+            }
+            if mask & (SU_VELOCITY1 << i) != 0 {
+                writer.write_i8(to_char(vel[i] / 16.0))?;
+            }
+        }
+        writer.write_u32::<LittleEndian>(*items)?;
+        if mask & SU_WEAPONFRAME != 0 {
+            writer.write_u8(*weaponframe)?;
+        }
+        if mask & SU_ARMOR != 0 {
+            writer.write_u8(*armorvalue)?;
+        }
+        if mask & SU_WEAPON != 0 {
+            writer.write_u8(*weaponmodel)?;
+        }
+        writer.write_i16::<LittleEndian>(*health)?;
+        writer.write_u8(*currentammo)?;
+        writer.write_u8(*ammo_shells)?;
+        writer.write_u8(*ammo_nails)?;
+        writer.write_u8(*ammo_rockets)?;
+        writer.write_u8(*ammo_cells)?;
+        writer.write_u8(*weapon)?;
+
+        Ok(())
+    }
+
+    fn serialize_update_entity<W>(&self, writer: &mut W) -> anyhow::Result<()>
+    where
+        W: Write,
+    {
+        let Self::Updateentity {
+            mask,
+            entity,
+            modelindex,
+            frame,
+            colormap,
+            skin,
+            effects,
+            origin,
+            angles,
+        } = self
+        else {
+            bail!("not an entity update message");
+        };
+
+        // The second byte of the mask is only sent when any of its bits is set.
+        let mut mask = *mask & !U_MOREBITS;
+        if mask & 0xff00 != 0 {
+            mask |= U_MOREBITS;
+        }
+        if *entity > 0xff {
+            mask |= U_LONGENTITY | U_MOREBITS;
+        }
+
+        let [low_bits, high_bits] = mask.to_le_bytes();
+        writer.write_u8(low_bits | UPDATE_ENTITY_FLAG)?;
+        if mask & U_MOREBITS != 0 {
+            writer.write_u8(high_bits)?;
+        }
+
+        if mask & U_LONGENTITY != 0 {
+            writer.write_u16::<LittleEndian>(*entity)?;
+        } else {
+            writer.write_u8(entity.to_le_bytes()[0])?;
+        }
+
+        for (bit, value) in [
+            (U_MODEL, modelindex),
+            (U_FRAME, frame),
+            (U_COLORMAP, colormap),
+            (U_SKIN, skin),
+            (U_EFFECTS, effects),
+        ] {
+            if mask & bit != 0 {
+                writer.write_u8(*value)?;
+            }
+        }
+
+        for (i, (origin_bit, angle_bit)) in [
+            (U_ORIGIN1, U_ANGLE1),
+            (U_ORIGIN2, U_ANGLE2),
+            (U_ORIGIN3, U_ANGLE3),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            if mask & origin_bit != 0 {
+                write_coord(writer, origin[i])?;
+            }
+            if mask & angle_bit != 0 {
+                write_angle(writer, angles[i])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Strings are null terminated and encoded with the Quake character set, every byte is kept as the code point of the
+// same value so that they survive a round trip.
+fn read_string<R>(reader: &mut R) -> anyhow::Result<String>
+where
+    R: ReadSeek,
+{
+    let mut text = String::new();
+    loop {
+        match reader.read_u8()? {
+            0 => break,
+            c => text.push(char::from(c)),
+        }
+    }
+
+    Ok(text)
+}
+
+fn write_string<W>(writer: &mut W, text: &str) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    for c in text.chars() {
+        writer.write_u8(u8::try_from(c).unwrap_or(b'?'))?;
+    }
+    writer.write_u8(0)?;
+
+    Ok(())
+}
+
+// A list of strings is terminated by an empty string.
+fn read_string_list<R>(reader: &mut R) -> anyhow::Result<Box<[String]>>
+where
+    R: ReadSeek,
+{
+    let mut list = Vec::new();
+    loop {
+        let text = read_string(reader)?;
+        if text.is_empty() {
+            break;
+        }
+        list.push(text);
+    }
+
+    Ok(list.into_boxed_slice())
+}
+
+fn write_string_list<W>(writer: &mut W, list: &[String]) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    for text in list {
+        write_string(writer, text)?;
+    }
+    writer.write_u8(0)?;
+
+    Ok(())
+}
+
+// Coordinates are sent as fixed point numbers with 3 fractional bits.
+fn read_coord<R>(reader: &mut R) -> anyhow::Result<f32>
+where
+    R: ReadSeek,
+{
+    Ok(f32::from(reader.read_i16::<LittleEndian>()?) / 8.0)
+}
+
+fn write_coord<W>(writer: &mut W, coord: f32) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    #[allow(clippy::cast_possible_truncation)]
+    let coord = (coord * 8.0).round() as i16;
+    writer.write_i16::<LittleEndian>(coord)?;
+
+    Ok(())
+}
+
+fn read_coords<R>(reader: &mut R) -> anyhow::Result<[f32; 3]>
+where
+    R: ReadSeek,
+{
+    Ok([
+        read_coord(reader)?,
+        read_coord(reader)?,
+        read_coord(reader)?,
+    ])
+}
+
+fn write_coords<W>(writer: &mut W, coords: [f32; 3]) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    coords.into_iter().try_for_each(|c| write_coord(writer, c))
+}
+
+// Angles are sent as a single byte covering a full turn.
+fn read_angle<R>(reader: &mut R) -> anyhow::Result<f32>
+where
+    R: ReadSeek,
+{
+    Ok(f32::from(reader.read_i8()?) * 360.0 / 256.0)
+}
+
+fn write_angle<W>(writer: &mut W, angle: f32) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    writer.write_i8(to_char(angle * 256.0 / 360.0))?;
+
+    Ok(())
+}
+
+fn read_angles<R>(reader: &mut R) -> anyhow::Result<[f32; 3]>
+where
+    R: ReadSeek,
+{
+    Ok([
+        read_angle(reader)?,
+        read_angle(reader)?,
+        read_angle(reader)?,
+    ])
+}
+
+fn write_angles<W>(writer: &mut W, angles: [f32; 3]) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    angles.into_iter().try_for_each(|a| write_angle(writer, a))
+}
+
+// Spawn messages interleave the coordinate and the angle of every axis.
+fn read_coords_and_angles<R>(reader: &mut R) -> anyhow::Result<([f32; 3], [f32; 3])>
+where
+    R: ReadSeek,
+{
+    let mut origin = [0.0; 3];
+    let mut angles = [0.0; 3];
+    for i in 0..3 {
+        origin[i] = read_coord(reader)?;
+        angles[i] = read_angle(reader)?;
+    }
+
+    Ok((origin, angles))
+}
+
+fn write_coords_and_angles<W>(
+    writer: &mut W,
+    origin: [f32; 3],
+    angles: [f32; 3],
+) -> anyhow::Result<()>
+where
+    W: Write,
+{
+    for i in 0..3 {
+        write_coord(writer, origin[i])?;
+        write_angle(writer, angles[i])?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+const fn to_byte(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[allow(clippy::cast_possible_truncation)]
+const fn to_char(value: f32) -> i8 {
+    // Angles wrap around, so they are reduced to the byte range before the conversion.
+    i8::from_le_bytes([(value.round() as i32).to_le_bytes()[0]])
 }
 
 #[derive(Clone, Copy, Debug, FromPrimitive, ToPrimitive)]
@@ -344,23 +1395,39 @@ pub enum ClientMessage {
 }
 
 pub trait MessageStream: Send + Sync {
-    fn next(&mut self) -> anyhow::Result<Message>;
+    // Returns the next message, or `None` once the stream is over.
+    fn next(&mut self) -> anyhow::Result<Option<Message>>;
 }
 
-struct FileMessageStream<R> {
+pub(crate) struct FileMessageStream<R> {
     reader: R,
+    data_offset: u64,
 }
 
+// Largest block the engine can send, the size of its network message buffer.
+const MAX_BLOCK_LENGTH: u32 = 65536;
+
 impl<R> FileMessageStream<R>
 where
     R: ReadSeek,
 {
-    fn new(reader: R) -> Self {
-        Self { reader }
+    pub(crate) fn new(mut reader: R) -> anyhow::Result<Self> {
+        // Demo files start with the forced CD track as text in its own line.
+        loop {
+            if reader.read_u8()? == b'\n' {
+                break;
+            }
+        }
+        let data_offset = reader.stream_position()?;
+
+        Ok(Self {
+            reader,
+            data_offset,
+        })
     }
 
     fn reset(&mut self) -> anyhow::Result<()> {
-        self.reader.seek(SeekFrom::Start(0))?;
+        self.reader.seek(SeekFrom::Start(self.data_offset))?;
 
         Ok(())
     }
@@ -370,18 +1437,163 @@ impl<R> MessageStream for FileMessageStream<R>
 where
     R: ReadSeek,
 {
-    fn next(&mut self) -> anyhow::Result<Message> {
-        let block_length = self.reader.read_i32::<LittleEndian>()?;
+    fn next(&mut self) -> anyhow::Result<Option<Message>> {
+        // Running out of data right at a block boundary is the regular end of the demo.
+        let block_length = match self.reader.read_u32::<LittleEndian>() {
+            Ok(block_length) => block_length,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
         let angles = [
             self.reader.read_f32::<LittleEndian>()?,
             self.reader.read_f32::<LittleEndian>()?,
             self.reader.read_f32::<LittleEndian>()?,
         ];
-        let messages = Box::new(
-            [0..block_length].map(|_| ServerMessage::deserialize(&mut self.reader).unwrap()),
-        );
 
-        Ok(Message::Block { angles, messages })
+        if block_length > MAX_BLOCK_LENGTH {
+            bail!("block too long: {}", block_length);
+        }
+        let mut buf = vec![0; usize::try_from(block_length)?];
+        self.reader.read_exact(&mut buf)?;
+
+        // The block length is known up front, so a malformed message only costs the rest of its own block.
+        let mut block_reader = Cursor::new(buf);
+        let mut messages = Vec::new();
+        while block_reader.position() < u64::from(block_length) {
+            match ServerMessage::deserialize(&mut block_reader) {
+                Ok(message @ ServerMessage::UnknownOpcode { code }) => {
+                    tracing::warn!("skipping block after unknown message id: {}", code);
+                    messages.push(message);
+                    break;
+                }
+                Ok(message) => messages.push(message),
+                Err(e) => {
+                    tracing::warn!("skipping block after malformed message: {:#}", e);
+                    break;
+                }
+            }
+        }
+
+        Ok(Some(Message::Block {
+            angles,
+            messages: messages.into_boxed_slice(),
+        }))
+    }
+}
+
+pub struct DemoWriter<W> {
+    writer: W,
+}
+
+impl<W> DemoWriter<W>
+where
+    W: Write,
+{
+    // Writes the demo header, a CD track of -1 lets the game pick the track from the CdTrack message.
+    pub fn new(mut writer: W, cd_track: i32) -> anyhow::Result<Self> {
+        writeln!(writer, "{cd_track}")?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write_block(
+        &mut self,
+        angles: [f32; 3],
+        messages: &[ServerMessage],
+    ) -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        for message in messages {
+            message.serialize(&mut buf)?;
+        }
+
+        self.writer
+            .write_u32::<LittleEndian>(u32::try_from(buf.len())?)?;
+        for angle in angles {
+            self.writer.write_f32::<LittleEndian>(angle)?;
+        }
+        self.writer.write_all(&buf)?;
+
+        Ok(())
+    }
+
+    pub fn write_message(&mut self, message: &Message) -> anyhow::Result<()> {
+        match message {
+            Message::Block { angles, messages } => self.write_block(*angles, messages),
+            Message::Server(_) | Message::Client(_) => bail!("only message blocks can be recorded"),
+        }
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Demo being recorded.  The stream it records shares it, so that the recording can be stopped while the stream keeps
+// playing.
+#[derive(Clone, Default)]
+pub struct DemoRecorder {
+    writer: Arc<Mutex<Option<DemoWriter<BufWriter<File>>>>>,
+}
+
+impl DemoRecorder {
+    #[must_use]
+    pub(crate) fn is_recording(&self) -> bool {
+        self.writer.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn start<P: AsRef<Path>>(&self, file_path: P, cd_track: i32) -> anyhow::Result<()> {
+        let writer = DemoWriter::new(BufWriter::new(File::create(file_path)?), cd_track)?;
+        *self.writer.lock().unwrap() = Some(writer);
+
+        Ok(())
+    }
+
+    // Returns whether a demo was being recorded.
+    pub(crate) fn stop(&self) -> anyhow::Result<bool> {
+        let Some(mut writer) = self.writer.lock().unwrap().take() else {
+            return Ok(false);
+        };
+        writer.flush()?;
+
+        Ok(true)
+    }
+
+    // A failed write ends the recording, what was written until then is kept.
+    fn record(&self, message: &Message) {
+        let mut writer = self.writer.lock().unwrap();
+        if let Some(Err(e)) = writer.as_mut().map(|writer| writer.write_message(message)) {
+            tracing::warn!("demo recording stopped: {:#}", e);
+            *writer = None;
+        }
+    }
+}
+
+// Writes the blocks read from the stream to the demo being recorded, the recording ends along with the stream.
+struct RecordingMessageStream {
+    stream: Box<dyn MessageStream>,
+    recorder: DemoRecorder,
+}
+
+impl MessageStream for RecordingMessageStream {
+    fn next(&mut self) -> anyhow::Result<Option<Message>> {
+        let result = self.stream.next();
+        match &result {
+            Ok(Some(message @ Message::Block { .. })) => self.recorder.record(message),
+            Ok(Some(_)) => (),
+            Ok(None) | Err(_) => {
+                if let Err(e) = self.recorder.stop() {
+                    tracing::warn!("couldn't finish the demo: {:#}", e);
+                }
+            }
+        }
+
+        result
     }
 }
 
@@ -406,12 +1618,20 @@ impl<R> MessageStream for QueueMessageStream<R>
 where
     R: ReadSeek,
 {
-    fn next(&mut self) -> anyhow::Result<Message> {
+    fn next(&mut self) -> anyhow::Result<Option<Message>> {
+        if self.readers.is_empty() {
+            return Ok(None);
+        }
+
         let i = self.reader_index % self.readers.len();
         let reader = &mut self.readers[i];
         match reader.next() {
-            Ok(message) => Ok(message),
-            Err(_) => {
+            Ok(Some(message)) => Ok(Some(message)),
+            result => {
+                // A broken demo must not stop the loop, it is rewound like a finished one and the next demo starts.
+                if let Err(e) = result {
+                    tracing::warn!("skipping demo after read error: {:#}", e);
+                }
                 reader.reset()?;
 
                 self.reader_index += 1;
@@ -428,13 +1648,128 @@ pub enum MessageSource {
 }
 
 #[system]
-pub fn message_handler(#[resource] message_stream: &mut Option<MessageSource>) {
-    if let Some(source) = message_stream {
-        let message = match source {
-            MessageSource::Local(message_stream) => message_stream.next().unwrap(),
-            MessageSource::Network(message_stream) => todo!(),
+pub fn message_handler(
+    #[resource] message_stream: &mut Option<MessageSource>,
+    #[resource] world_time: &mut WorldTime,
+    #[resource] resource_files: &ResourceFiles,
+    #[resource] loading: &mut Option<Loading>,
+    #[resource] connection: &mut Connection,
+    #[resource] console: &mut Console,
+    #[resource] hud: &mut Hud,
+) {
+    connection.clear_events();
+
+    // Nothing else is read until the level has been loaded.
+    if loading.is_some() {
+        return;
+    }
+
+    // Blocks are read until the stream is ahead of the client again, so playback follows the server clock instead
+    // of the frame rate.
+    while world_time.needs_update() && loading.is_none() {
+        let result = match message_stream {
+            Some(MessageSource::Local(stream) | MessageSource::Network(stream)) => stream.next(),
+            None => return,
         };
+
+        match result {
+            Ok(Some(Message::Block { angles, messages })) => {
+                connection.set_view_angles(angles);
+                messages.iter().for_each(|message| {
+                    connection.handle_server_message(message);
+                    hud.handle_server_message(message, world_time.time());
+                    handle_server_message(message, world_time, resource_files, loading, console);
+                });
+            }
+            Ok(Some(Message::Server(message))) => {
+                connection.handle_server_message(&message);
+                hud.handle_server_message(&message, world_time.time());
+                handle_server_message(&message, world_time, resource_files, loading, console);
+            }
+            Ok(Some(Message::Client(_))) => (),
+            Ok(None) => {
+                *message_stream = None;
+                connection.disconnect();
+                return;
+            }
+            Err(e) => {
+                tracing::warn!("message stream stopped: {:#}", e);
+                *message_stream = None;
+                connection.disconnect();
+                return;
+            }
+        }
     }
+    world_time.clamp();
+}
+
+fn handle_server_message(
+    message: &ServerMessage,
+    world_time: &mut WorldTime,
+    resource_files: &ResourceFiles,
+    loading: &mut Option<Loading>,
+    console: &mut Console,
+) {
+    match message {
+        ServerMessage::Time { time } => world_time.set_server_time(*time),
+        ServerMessage::Print { text } => console.print(text),
+        // A new level starts with its own clock, once everything it needs has been loaded.
+        ServerMessage::ServerInfo {
+            mapname,
+            precache_sounds,
+            ..
+        } => {
+            world_time.reset();
+            let sounds = SoundRegistry::load(mapname, precache_sounds, resource_files);
+            *loading = Some(Loading::new(mapname.clone(), sounds));
+        }
+        _ => (),
+    }
+}
+
+// A stream started while recording is recorded.
+fn local_source(stream: Box<dyn MessageStream>, demo_recorder: &DemoRecorder) -> MessageSource {
+    if demo_recorder.is_recording() {
+        MessageSource::Local(Box::new(RecordingMessageStream {
+            stream,
+            recorder: demo_recorder.clone(),
+        }))
+    } else {
+        MessageSource::Local(stream)
+    }
+}
+
+// Ending the playback ends its recording too.
+fn stop_playback(
+    message_stream: &mut Option<MessageSource>,
+    connection: &mut Connection,
+    demo_recorder: &DemoRecorder,
+    console: &mut Console,
+) {
+    if message_stream.take().is_some() {
+        connection.disconnect();
+        if let Err(e) = demo_recorder.stop() {
+            console.print_warning(&format!("couldn't finish the demo: {e:#}"));
+        }
+    }
+}
+
+// Demos are only written inside the game directory, like the original engine does.
+fn demo_path(resource_files: &ResourceFiles, file_name: &str) -> anyhow::Result<PathBuf> {
+    let file_path = Path::new(file_name);
+    if !file_path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("relative and absolute paths are not allowed: {}", file_name);
+    }
+
+    let mut file_path = resource_files.dir_path().join(file_path);
+    if file_path.extension().is_none() {
+        file_path.set_extension("dem");
+    }
+
+    Ok(file_path)
 }
 
 #[system]
@@ -442,35 +1777,416 @@ pub fn message_command_executor(
     #[resource] message_stream: &mut Option<MessageSource>,
     #[resource] console: &mut Console,
     #[resource] resource_files: &mut ResourceFiles,
+    #[resource] connection: &mut Connection,
+    #[resource] demo_recorder: &DemoRecorder,
 ) {
-    console.commands().for_each(|command| match &command[..] {
-        // Play a demo.
-        [ref cmd, file_path] if cmd == "playdemo" => {
-            let reader = resource_files.take(file_path).unwrap();
-            let file_stream = FileMessageStream::new(reader);
-            *message_stream = Some(MessageSource::Local(Box::new(file_stream)));
-        }
-        // Stops the current playback of demos.
-        [ref cmd] if cmd == "stopdemo" => {
-            if let Some(MessageSource::Local(_)) = message_stream {
-                *message_stream = None;
+    // The commands are copied so that the console can be printed to while they run.
+    let commands: Vec<ConsoleCmd> = console.commands().cloned().collect();
+    for command in &commands {
+        match &command[..] {
+            // Play a demo.
+            [ref cmd, file_path] if cmd == "playdemo" => {
+                match resource_files
+                    .take(file_path)
+                    .and_then(FileMessageStream::new)
+                {
+                    Ok(file_stream) => {
+                        stop_playback(message_stream, connection, demo_recorder, console);
+                        *message_stream = Some(local_source(Box::new(file_stream), demo_recorder));
+                        connection.connect();
+                    }
+                    Err(e) => {
+                        console.print_warning(&format!("couldn't play demo {file_path}: {e:#}"));
+                    }
+                }
+            }
+            // Stops the current playback of demos.
+            [ref cmd] if cmd == "stopdemo" => {
+                if let Some(MessageSource::Local(_)) = message_stream {
+                    stop_playback(message_stream, connection, demo_recorder, console);
+                }
+            }
+            // Setup a queue of demos to loop.
+            [ref cmd, file_paths @ ..] if cmd == "startdemos" => {
+                let mut queue = VecDeque::new();
+                for file_path in file_paths {
+                    match resource_files
+                        .take(format!("{file_path}.dem"))
+                        .and_then(FileMessageStream::new)
+                    {
+                        Ok(file_stream) => queue.push_back(file_stream),
+                        Err(e) => {
+                            console.print_warning(&format!("skipping demo {file_path}: {e:#}"));
+                        }
+                    }
+                }
+                let queue_stream = QueueMessageStream::new(queue);
+                stop_playback(message_stream, connection, demo_recorder, console);
+                *message_stream = Some(local_source(Box::new(queue_stream), demo_recorder));
+                connection.connect();
             }
+            // Records the messages of the next playback to a demo in the game directory, like the original engine it
+            // has to be started before the playback.  A CD track of -1 lets the demo pick its own.
+            [ref cmd, file_name, args @ ..] if cmd == "record" && args.len() <= 1 => {
+                if message_stream.is_some() {
+                    console.print_warning(
+                        "can't record while a demo is playing, start the recording before it",
+                    );
+                    continue;
+                }
+
+                let cd_track = match args.first().map(|arg| arg.parse::<i32>()) {
+                    Some(Ok(cd_track)) => cd_track,
+                    Some(Err(_)) => {
+                        console.print_warning(&format!("invalid CD track: {}", args[0]));
+                        continue;
+                    }
+                    None => -1,
+                };
+                match demo_path(resource_files, file_name).and_then(|file_path| {
+                    demo_recorder.start(&file_path, cd_track)?;
+                    Ok(file_path)
+                }) {
+                    Ok(file_path) => {
+                        console.print(&format!("recording to {}", file_path.display()));
+                    }
+                    Err(e) => {
+                        console.print_warning(&format!("couldn't record demo {file_name}: {e:#}"));
+                    }
+                }
+            }
+            // Stops recording a demo.
+            [ref cmd] if cmd == "stop" => match demo_recorder.stop() {
+                Ok(true) => console.print("completed demo"),
+                Ok(false) => console.print_warning("not recording a demo"),
+                Err(e) => console.print_warning(&format!("couldn't finish the demo: {e:#}")),
+            },
+            _ => (),
         }
-        // Setup a queue of demos to loop.
-        [ref cmd, file_paths @ ..] if cmd == "startdemos" => {
-            let queue = file_paths
-                .iter()
-                .map(|file_path| {
-                    let reader = resource_files
-                        .take(format!("{}.dem", file_path).as_str())
-                        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every value survives the fixed point encoding: coordinates are multiples of 1/8 and angles of 360/256 degrees.
+    fn temp_entities() -> Vec<TempEntity> {
+        let origin = [-16.125, 32.5, 1024.0];
+        let start = [8.0, -8.25, 0.0];
+        let end = [512.875, 64.0, -24.5];
+        vec![
+            TempEntity::Spike { origin },
+            TempEntity::SuperSpike { origin },
+            TempEntity::Gunshot { origin },
+            TempEntity::Explosion { origin },
+            TempEntity::TarExplosion { origin },
+            TempEntity::Lightning1 {
+                entity: 12,
+                start,
+                end,
+            },
+            TempEntity::Lightning2 {
+                entity: 1,
+                start,
+                end,
+            },
+            TempEntity::WizSpike { origin },
+            TempEntity::KnightSpike { origin },
+            TempEntity::Lightning3 {
+                entity: 300,
+                start,
+                end,
+            },
+            TempEntity::LavaSplash { origin },
+            TempEntity::Teleport { origin },
+            TempEntity::Explosion2 {
+                origin,
+                color_start: 192,
+                color_length: 8,
+            },
+            TempEntity::Beam {
+                entity: 7,
+                start,
+                end,
+            },
+        ]
+    }
+
+    #[allow(clippy::too_many_lines)]
+    fn server_messages() -> Vec<ServerMessage> {
+        let origin = [128.0, -256.375, 40.125];
+        let angles = [-45.0, 90.0, 178.59375];
+        let mut messages = vec![
+            ServerMessage::Bad,
+            ServerMessage::Nop,
+            ServerMessage::Disconnect,
+            ServerMessage::UpdateStat {
+                index: 14,
+                value: -3,
+            },
+            ServerMessage::Version { version: 15 },
+            ServerMessage::SetView { entity: 1 },
+            ServerMessage::Sound {
+                mask: SND_VOLUME | SND_ATTENUATION,
+                volume: 128,
+                attenuation: 1.5,
+                channel: 3,
+                entity_id: 4000,
+                sound_id: 27,
+                origin,
+            },
+            ServerMessage::Sound {
+                mask: 0,
+                volume: 255,
+                attenuation: 1.0,
+                channel: 0,
+                entity_id: 1,
+                sound_id: 2,
+                origin,
+            },
+            ServerMessage::Time { time: 12.345 },
+            ServerMessage::Print {
+                text: "You got the shells\n".to_string(),
+            },
+            ServerMessage::StuffText {
+                text: "bf\n".to_string(),
+            },
+            ServerMessage::SetAngle { angles },
+            ServerMessage::ServerInfo {
+                serverversion: 15,
+                maxclients: 1,
+                multi: 0,
+                mapname: "the Slipgate Complex".to_string(),
+                precache_models: vec!["maps/e1m1.bsp".to_string(), "*1".to_string()]
+                    .into_boxed_slice(),
+                precache_sounds: vec!["weapons/r_exp3.wav".to_string()].into_boxed_slice(),
+            },
+            ServerMessage::LightStyle {
+                style: 1,
+                lightmap: "mmnmmommommnonmmonqnmmo".to_string(),
+            },
+            ServerMessage::UpdateName {
+                player: 0,
+                netname: "player".to_string(),
+            },
+            ServerMessage::UpdateFrags {
+                player: 0,
+                frags: -2,
+            },
+            ServerMessage::PlayerData {
+                mask: SU_VIEWHEIGHT
+                    | SU_IDEALPITCH
+                    | SU_PUNCH1
+                    | (SU_PUNCH1 << 2)
+                    | SU_VELOCITY1
+                    | (SU_VELOCITY1 << 1)
+                    | SU_ITEMS
+                    | SU_ONGROUND
+                    | SU_WEAPONFRAME
+                    | SU_ARMOR
+                    | SU_WEAPON,
+                view_ofs_z: 22.0,
+                ang_ofs_1: -10.0,
+                angles: [2.0, 0.0, -3.0],
+                vel: [320.0, -160.0, 0.0],
+                items: 0x4001,
+                weaponframe: 3,
+                armorvalue: 100,
+                weaponmodel: 68,
+                health: 100,
+                currentammo: 25,
+                ammo_shells: 25,
+                ammo_nails: 0,
+                ammo_rockets: 5,
+                ammo_cells: 0,
+                weapon: 1,
+            },
+            ServerMessage::PlayerData {
+                mask: 0,
+                view_ofs_z: 22.0,
+                ang_ofs_1: 0.0,
+                angles: [0.0; 3],
+                vel: [0.0; 3],
+                items: 0,
+                weaponframe: 0,
+                armorvalue: 0,
+                weaponmodel: 0,
+                health: -5,
+                currentammo: 0,
+                ammo_shells: 0,
+                ammo_nails: 0,
+                ammo_rockets: 0,
+                ammo_cells: 0,
+                weapon: 0,
+            },
+            ServerMessage::StopSound {
+                entity_id: 4000,
+                channel: 7,
+            },
+            ServerMessage::UpdateColors {
+                player: 0,
+                colors: 0x4d,
+            },
+            ServerMessage::Particle {
+                origin,
+                vel: [0.5, -1.0, 7.9375],
+                count: 20,
+                color: 73,
+            },
+            ServerMessage::Damage {
+                save: 3,
+                take: 12,
+                origin,
+            },
+            ServerMessage::SpawnStatic {
+                default_modelindex: 45,
+                default_frame: 1,
+                default_colormap: 0,
+                default_skin: 2,
+                default_origin: origin,
+                default_angles: angles,
+            },
+            ServerMessage::SpawnBaseline {
+                entity: 300,
+                default_modelindex: 2,
+                default_frame: 0,
+                default_colormap: 1,
+                default_skin: 0,
+                default_origin: origin,
+                default_angles: angles,
+            },
+        ];
+        messages.extend(
+            temp_entities()
+                .into_iter()
+                .map(|entity| ServerMessage::TempEntity { entity }),
+        );
+        messages.extend([
+            ServerMessage::SetPause { pausestate: 1 },
+            ServerMessage::SignOnStage { signon: 2 },
+            ServerMessage::CenterPrint {
+                text: "The door is locked".to_string(),
+            },
+            ServerMessage::KilledMonster,
+            ServerMessage::FoundSecret,
+            ServerMessage::SpawnStaticSound {
+                origin,
+                soundnum: 9,
+                vol: 1.0,
+                attenuation: 3.0,
+            },
+            ServerMessage::Intermission,
+            ServerMessage::Finale {
+                text: "As the corpse of the monstrous entity\n".to_string(),
+            },
+            ServerMessage::CdTrack {
+                fromtrack: 4,
+                totrack: 4,
+            },
+            ServerMessage::SellScreen,
+            ServerMessage::CutScene {
+                text: "\n".to_string(),
+            },
+            ServerMessage::Updateentity {
+                mask: U_MOREBITS
+                    | U_ORIGIN1
+                    | U_ORIGIN2
+                    | U_ORIGIN3
+                    | U_ANGLE2
+                    | U_NOLERP
+                    | U_FRAME
+                    | U_ANGLE1
+                    | U_ANGLE3
+                    | U_MODEL
+                    | U_COLORMAP
+                    | U_SKIN
+                    | U_EFFECTS
+                    | U_LONGENTITY,
+                entity: 300,
+                modelindex: 12,
+                frame: 5,
+                colormap: 1,
+                skin: 1,
+                effects: 4,
+                origin,
+                angles,
+            },
+            ServerMessage::Updateentity {
+                mask: U_ORIGIN1 | U_FRAME,
+                entity: 12,
+                modelindex: 0,
+                frame: 9,
+                colormap: 0,
+                skin: 0,
+                effects: 0,
+                origin: [-8.0, 0.0, 0.0],
+                angles: [0.0; 3],
+            },
+        ]);
+        messages
+    }
 
-                    FileMessageStream::new(reader)
-                })
-                .collect();
-            let queue_stream = QueueMessageStream::new(queue);
-            *message_stream = Some(MessageSource::Local(Box::new(queue_stream)));
+    #[test]
+    fn temp_entities_round_trip() {
+        for entity in temp_entities() {
+            let mut buf = Vec::new();
+            entity.serialize(&mut buf).unwrap();
+            let decoded = TempEntity::deserialize(&mut Cursor::new(buf)).unwrap();
+            assert_eq!(decoded, entity);
         }
-        _ => (),
-    });
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for message in server_messages() {
+            let mut buf = Vec::new();
+            message.serialize(&mut buf).unwrap();
+            let mut reader = Cursor::new(buf);
+            let decoded = ServerMessage::deserialize(&mut reader).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(reader.position(), reader.get_ref().len() as u64);
+        }
+    }
+
+    #[test]
+    fn unknown_message_ids_are_not_written() {
+        let message = ServerMessage::UnknownOpcode { code: 0x15 };
+        assert!(message.serialize(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn demo_round_trip() {
+        let messages = server_messages();
+        let angles = [10.0, -20.5, 0.25];
+
+        let mut demo_writer = DemoWriter::new(Vec::new(), -1).unwrap();
+        demo_writer.write_block(angles, &messages).unwrap();
+        demo_writer
+            .write_message(&Message::Block {
+                angles: [0.0; 3],
+                messages: Box::new([ServerMessage::Nop]),
+            })
+            .unwrap();
+        assert!(demo_writer
+            .write_message(&Message::Server(ServerMessage::Nop))
+            .is_err());
+
+        let mut stream = FileMessageStream::new(Cursor::new(demo_writer.into_inner())).unwrap();
+        let Some(Message::Block {
+            angles: decoded_angles,
+            messages: decoded,
+        }) = stream.next().unwrap()
+        else {
+            panic!("expected a message block");
+        };
+        assert_eq!(decoded_angles, angles);
+        assert_eq!(&decoded[..], &messages[..]);
+
+        assert!(matches!(
+            stream.next().unwrap(),
+            Some(Message::Block { messages, .. }) if messages[..] == [ServerMessage::Nop]
+        ));
+        assert!(stream.next().unwrap().is_none());
+    }
 }
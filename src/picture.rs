@@ -0,0 +1,189 @@
+use std::io::{Read, SeekFrom};
+
+use anyhow::bail;
+use byteorder::{LittleEndian, ReadBytesExt};
+use indexmap::IndexMap;
+
+use crate::ReadSeek;
+
+// Color index used by 2D pictures for transparent pixels.
+const TRANSPARENT_INDEX: u8 = 255;
+
+pub struct Palette {
+    colors: Box<[[u8; 3]]>,
+}
+
+impl Palette {
+    // Reads the 256 RGB colors of gfx/palette.lmp.
+    pub fn new<R>(mut reader: R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let mut colors = vec![[0u8; 3]; 256];
+        for color in &mut colors {
+            reader.read_exact(color)?;
+        }
+
+        Ok(Self {
+            colors: colors.into_boxed_slice(),
+        })
+    }
+
+    #[must_use]
+    pub fn to_rgba(&self, indices: &[u8]) -> Vec<u8> {
+        indices
+            .iter()
+            .flat_map(|&i| {
+                let [r, g, b] = self.colors[usize::from(i)];
+                let a = if i == TRANSPARENT_INDEX { 0 } else { 255 };
+                [r, g, b, a]
+            })
+            .collect()
+    }
+}
+
+// A 2D image made of palette indices, such as the .lmp files in gfx/ or the pictures inside gfx.wad.
+pub struct Picture {
+    width: u32,
+    height: u32,
+    indices: Box<[u8]>,
+}
+
+impl Picture {
+    #[must_use]
+    pub const fn new(width: u32, height: u32, indices: Box<[u8]>) -> Self {
+        Self {
+            width,
+            height,
+            indices,
+        }
+    }
+
+    // Reads a picture made of its size followed by its pixels.
+    pub fn from_lmp<R>(mut reader: R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let width = reader.read_u32::<LittleEndian>()?;
+        let height = reader.read_u32::<LittleEndian>()?;
+        let Some(length) = width.checked_mul(height) else {
+            bail!("invalid picture size: {}x{}", width, height);
+        };
+
+        let indices = read_bytes(&mut reader, length)?;
+
+        Ok(Self::new(width, height, indices))
+    }
+
+    #[must_use]
+    pub const fn width(&self) -> u32 {
+        self.width
+    }
+
+    #[must_use]
+    pub const fn height(&self) -> u32 {
+        self.height
+    }
+
+    #[must_use]
+    pub fn to_rgba(&self, palette: &Palette) -> Vec<u8> {
+        palette.to_rgba(&self.indices)
+    }
+}
+
+// Reads a block whose length comes from the file itself, the buffer grows with the data actually read so that a bogus
+// length cannot allocate more than the file holds.
+fn read_bytes<R: ReadSeek>(reader: &mut R, length: u32) -> anyhow::Result<Box<[u8]>> {
+    let mut buf = Vec::new();
+    reader.take(u64::from(length)).read_to_end(&mut buf)?;
+    if buf.len() != usize::try_from(length)? {
+        bail!("unexpected end of file");
+    }
+
+    Ok(buf.into_boxed_slice())
+}
+
+// A WAD2 archive, the format of gfx.wad which holds the pictures of the status bar and the console font.
+pub struct Wad {
+    lumps: IndexMap<String, Box<[u8]>>,
+}
+
+impl Wad {
+    pub fn new<R>(mut reader: R) -> anyhow::Result<Self>
+    where
+        R: ReadSeek,
+    {
+        let mut ident = [0u8; 4];
+        reader.read_exact(&mut ident)?;
+        if &ident != b"WAD2" {
+            bail!("invalid signature");
+        }
+
+        let lump_count = reader.read_u32::<LittleEndian>()?;
+        let dir_offset = reader.read_u32::<LittleEndian>()?;
+
+        let mut entries = Vec::new();
+        reader.seek(SeekFrom::Start(u64::from(dir_offset)))?;
+        for _ in 0..lump_count {
+            let lump_offset = reader.read_u32::<LittleEndian>()?;
+            let _disk_size = reader.read_u32::<LittleEndian>()?;
+            let lump_size = reader.read_u32::<LittleEndian>()?;
+            let _lump_type = reader.read_u8()?;
+            let compression = reader.read_u8()?;
+            let _padding = reader.read_u16::<LittleEndian>()?;
+
+            let mut buf = [0u8; 16];
+            reader.read_exact(&mut buf)?;
+            let name_length = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+            let lump_name = String::from_utf8_lossy(&buf[..name_length]).to_lowercase();
+
+            if compression != 0 {
+                bail!("compressed lumps are not supported: {}", lump_name);
+            }
+            entries.push((lump_name, lump_offset, lump_size));
+        }
+
+        let mut lumps = IndexMap::with_capacity(entries.len());
+        for (lump_name, lump_offset, lump_size) in entries {
+            reader.seek(SeekFrom::Start(u64::from(lump_offset)))?;
+            lumps.insert(lump_name, read_bytes(&mut reader, lump_size)?);
+        }
+
+        Ok(Self { lumps })
+    }
+
+    pub fn lump_names(&self) -> impl Iterator<Item = &str> {
+        self.lumps.keys().map(String::as_str)
+    }
+
+    #[must_use]
+    pub fn lump(&self, name: &str) -> Option<&[u8]> {
+        self.lumps.get(name).map(AsRef::as_ref)
+    }
+
+    pub fn picture(&self, name: &str) -> anyhow::Result<Picture> {
+        let Some(lump) = self.lump(name) else {
+            bail!("lump not found: {}", name);
+        };
+
+        Picture::from_lmp(std::io::Cursor::new(lump))
+    }
+
+    // The console font is stored as a raw 128x128 block with 16x16 characters of 8x8 pixels, and uses the color 0
+    // for the transparent pixels.
+    pub fn conchars(&self) -> anyhow::Result<Picture> {
+        let Some(lump) = self.lump("conchars") else {
+            bail!("lump not found: conchars");
+        };
+        if lump.len() < 128 * 128 {
+            bail!("invalid conchars size: {}", lump.len());
+        }
+
+        let indices = lump[..128 * 128]
+            .iter()
+            .map(|&i| if i == 0 { TRANSPARENT_INDEX } else { i })
+            .collect();
+
+        Ok(Picture::new(128, 128, indices))
+    }
+}
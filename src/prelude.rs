@@ -0,0 +1,15 @@
+// Types most users of the crate need, so that a single glob import brings them in.
+pub use crate::{
+    app::GameApp,
+    audio::{Audio, Pcm, PcmCache},
+    client::{Connection, ConnectionState, SignonStage},
+    console::{Console, ConsoleCmd, ConsoleInput, Cvar, CvarFlags, OutputLevel},
+    graphics::{Graphics, PictureId},
+    hud::{Hud, PlayerStats},
+    input::{Input, InputEvent},
+    message::{ClientMessage, Message, MessageSource, ServerMessage},
+    picture::{Palette, Picture, Wad},
+    screen::{Loading, Screen},
+    time::{Clock, ManualClock, RenderTime, SystemClock, WorldTime},
+    ResourceBundle, ResourceFiles,
+};
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+
+use crate::{
+    graphics::{Graphics, PictureId},
+    hud::{
+        PlayerStats, IT_ARMOR1, IT_ARMOR2, IT_ARMOR3, IT_CELLS, IT_INVISIBILITY,
+        IT_INVULNERABILITY, IT_KEY1, IT_NAILS, IT_QUAD, IT_ROCKETS, IT_SHELLS, IT_SHOTGUN,
+        IT_SIGIL1, STAT_ACTIVEWEAPON, STAT_AMMO, STAT_ARMOR, STAT_HEALTH, STAT_SHELLS,
+    },
+    picture::{Palette, Wad},
+    screen::Screen,
+};
+
+const SBAR_WIDTH: f32 = 320.0;
+const SBAR_HEIGHT: f32 = 24.0;
+const DIGIT_WIDTH: f32 = 24.0;
+
+// Pictures of the weapons in the inventory bar, in the order of their item bits.
+const WEAPONS: [&str; 7] = [
+    "shotgun", "sshotgun", "nailgun", "snailgun", "rlaunch", "srlaunch", "lightng",
+];
+// Pictures of the keys and powerups, in the order of their item bits.
+const ITEMS: [&str; 6] = [
+    "sb_key1",
+    "sb_key2",
+    "sb_invis",
+    "sb_invuln",
+    "sb_suit",
+    "sb_quad",
+];
+// Ammo icons with the item bit that selects them.
+const AMMO: [(u32, &str); 4] = [
+    (IT_SHELLS, "sb_shells"),
+    (IT_NAILS, "sb_nails"),
+    (IT_ROCKETS, "sb_rocket"),
+    (IT_CELLS, "sb_cells"),
+];
+// Armor icons with the item bit that selects them, the strongest first.
+const ARMOR: [(u32, &str); 3] = [
+    (IT_ARMOR3, "sb_armor3"),
+    (IT_ARMOR2, "sb_armor2"),
+    (IT_ARMOR1, "sb_armor1"),
+];
+
+// Status bar at the bottom of the screen with the inventory bar on top of it, laid out like the original engine
+// does on its 320 pixels wide screen.
+#[derive(Default)]
+pub struct Sbar {
+    pictures: HashMap<String, PictureId>,
+}
+
+impl Sbar {
+    // Every lump of gfx.wad is a picture of the status bar, except the console font.
+    pub fn new(graphics: &mut Graphics, wad: &Wad, palette: &Palette) -> Self {
+        let mut pictures = HashMap::new();
+        for name in wad.lump_names().filter(|name| *name != "conchars") {
+            match wad
+                .picture(name)
+                .and_then(|picture| graphics.upload_picture(&picture, palette))
+            {
+                Ok(picture) => {
+                    pictures.insert(name.to_owned(), picture);
+                }
+                Err(e) => tracing::warn!("couldn't load picture {}: {:#}", name, e),
+            }
+        }
+
+        Self { pictures }
+    }
+
+    pub fn draw(&self, graphics: &mut Graphics, screen: &Screen, stats: &PlayerStats, now: f32) {
+        let (width, height) = graphics.canvas_size();
+        let x = ((width - SBAR_WIDTH) / 2.0).floor();
+        let y = height - SBAR_HEIGHT;
+
+        self.draw_inventory(graphics, screen, stats, x, y - SBAR_HEIGHT);
+
+        self.draw_picture(graphics, "sbar", x, y);
+        if let Some((_, name)) = ARMOR.iter().find(|(item, _)| stats.has_item(*item)) {
+            self.draw_picture(graphics, name, x, y);
+        }
+        let armor = stats.stat(STAT_ARMOR);
+        self.draw_number(graphics, x + 24.0, y, armor, armor <= 25);
+
+        self.draw_picture(graphics, Self::face(stats, now), x + 112.0, y);
+        let health = stats.stat(STAT_HEALTH);
+        self.draw_number(graphics, x + 136.0, y, health, health <= 25);
+
+        if let Some((_, name)) = AMMO.iter().find(|(item, _)| stats.has_item(*item)) {
+            self.draw_picture(graphics, name, x + 224.0, y);
+        }
+        let ammo = stats.stat(STAT_AMMO);
+        self.draw_number(graphics, x + 248.0, y, ammo, ammo <= 10);
+    }
+
+    fn draw_inventory(
+        &self,
+        graphics: &mut Graphics,
+        screen: &Screen,
+        stats: &PlayerStats,
+        x: f32,
+        y: f32,
+    ) {
+        self.draw_picture(graphics, "ibar", x, y);
+
+        let active_weapon = u32::try_from(stats.stat(STAT_ACTIVEWEAPON)).unwrap_or(0);
+        for (i, weapon) in (0u16..).zip(WEAPONS) {
+            let item = IT_SHOTGUN << i;
+            if stats.has_item(item) {
+                let prefix = if active_weapon == item { "inv2" } else { "inv" };
+                let name = format!("{prefix}_{weapon}");
+                self.draw_picture(graphics, &name, x + f32::from(24 * i), y + 8.0);
+            }
+        }
+
+        // The ammo counts use the small yellow digits of the console font.
+        for i in 0..4u16 {
+            let count = format!(
+                "{:3}",
+                stats.stat(STAT_SHELLS + usize::from(i)).clamp(0, 999)
+            );
+            let digits: String = count
+                .chars()
+                .map(|c| {
+                    c.to_digit(10)
+                        .and_then(|d| char::from_u32(18 + d))
+                        .unwrap_or(' ')
+                })
+                .collect();
+            screen.draw_string(graphics, x + f32::from(48 * i + 6), y, &digits);
+        }
+
+        for (i, name) in (0u16..).zip(ITEMS) {
+            if stats.has_item(IT_KEY1 << i) {
+                self.draw_picture(graphics, name, x + f32::from(192 + 16 * i), y + 8.0);
+            }
+        }
+
+        for i in 0..4u16 {
+            if stats.has_item(IT_SIGIL1 << i) {
+                let name = format!("sb_sigil{}", i + 1);
+                self.draw_picture(graphics, &name, x + f32::from(288 + 8 * i), y + 8.0);
+            }
+        }
+    }
+
+    // Draws a number right aligned on three digits, the alternate digits are red.
+    fn draw_number(&self, graphics: &mut Graphics, x: f32, y: f32, number: i32, alternate: bool) {
+        let prefix = if alternate { "anum" } else { "num" };
+        let text = number.clamp(-99, 999).to_string();
+
+        #[allow(clippy::cast_precision_loss)]
+        let mut x = DIGIT_WIDTH.mul_add((3 - text.len()) as f32, x);
+        for c in text.chars() {
+            let name = if c == '-' {
+                format!("{prefix}_minus")
+            } else {
+                format!("{prefix}_{c}")
+            };
+            self.draw_picture(graphics, &name, x, y);
+            x += DIGIT_WIDTH;
+        }
+    }
+
+    fn face(stats: &PlayerStats, now: f32) -> &'static str {
+        const FACES: [&str; 5] = ["face5", "face4", "face3", "face2", "face1"];
+        const PAIN_FACES: [&str; 5] = ["face_p5", "face_p4", "face_p3", "face_p2", "face_p1"];
+
+        let powerups = IT_INVISIBILITY | IT_INVULNERABILITY;
+        if stats.items() & powerups == powerups {
+            return "face_inv2";
+        }
+        if stats.has_item(IT_QUAD) {
+            return "face_quad";
+        }
+        if stats.has_item(IT_INVISIBILITY) {
+            return "face_invis";
+        }
+        if stats.has_item(IT_INVULNERABILITY) {
+            return "face_invul1";
+        }
+
+        let index = usize::try_from(stats.stat(STAT_HEALTH) / 20)
+            .unwrap_or(0)
+            .min(4);
+        if stats.in_pain(now) {
+            PAIN_FACES[index]
+        } else {
+            FACES[index]
+        }
+    }
+
+    fn draw_picture(&self, graphics: &mut Graphics, name: &str, x: f32, y: f32) {
+        if let Some(picture) = self.pictures.get(name) {
+            graphics.draw_picture(*picture, x, y);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use legion::system;
+
+use crate::{
+    audio::SoundRegistry,
+    console::{Console, ConsoleInput, OutputLevel},
+    graphics::{Graphics, PictureId},
+    hud::{Hud, NOTIFY_LINES},
+    input::Input,
+    picture::{Palette, Picture, Wad},
+    sbar::Sbar,
+    time::{RenderTime, WorldTime},
+    ResourceFiles,
+};
+
+const CHAR_SIZE: f32 = 8.0;
+
+const PROGRESS_WIDTH: f32 = 160.0;
+const PROGRESS_HEIGHT: f32 = 8.0;
+const PROGRESS_BACKGROUND: [f32; 4] = [0.1, 0.1, 0.1, 1.0];
+const PROGRESS_FOREGROUND: [f32; 4] = [0.55, 0.4, 0.2, 1.0];
+
+const CONSOLE_BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.75];
+// Glyph drawn under the cursor of the console line.
+const CONSOLE_CURSOR: char = '\u{b}';
+
+// Level change in progress, the world is on hold until every resource the level needs has been loaded.
+pub struct Loading {
+    title: String,
+    sounds: SoundRegistry,
+}
+
+impl Loading {
+    #[must_use]
+    pub const fn new(title: String, sounds: SoundRegistry) -> Self {
+        Self { title, sounds }
+    }
+
+    #[must_use]
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    #[must_use]
+    pub fn loaded_bytes(&self) -> usize {
+        self.sounds.loaded_bytes()
+    }
+
+    #[must_use]
+    pub fn progress(&self) -> f32 {
+        self.sounds.progress()
+    }
+}
+
+// 2D pictures shared by everything drawn on top of the world.
+#[derive(Default)]
+pub struct Screen {
+    conchars: Option<PictureId>,
+    loading: Option<PictureId>,
+    sbar: Sbar,
+}
+
+impl Screen {
+    // Missing pictures are reported and left out, the client keeps running without them.
+    pub fn new(graphics: &mut Graphics, resource_files: &ResourceFiles) -> Self {
+        let palette = match resource_files
+            .take("gfx/palette.lmp")
+            .and_then(Palette::new)
+        {
+            Ok(palette) => palette,
+            Err(e) => {
+                tracing::warn!("couldn't load palette: {:#}", e);
+                return Self::default();
+            }
+        };
+
+        let mut upload = |name: &str, picture: anyhow::Result<Picture>| match picture
+            .and_then(|picture| graphics.upload_picture(&picture, &palette))
+        {
+            Ok(picture) => Some(picture),
+            Err(e) => {
+                tracing::warn!("couldn't load picture {}: {:#}", name, e);
+                None
+            }
+        };
+
+        let wad = match resource_files.take("gfx.wad").and_then(Wad::new) {
+            Ok(wad) => Some(wad),
+            Err(e) => {
+                tracing::warn!("couldn't load gfx.wad: {:#}", e);
+                None
+            }
+        };
+        let conchars = wad
+            .as_ref()
+            .and_then(|wad| upload("conchars", wad.conchars()));
+        let loading = upload(
+            "gfx/loading.lmp",
+            resource_files
+                .take("gfx/loading.lmp")
+                .and_then(Picture::from_lmp),
+        );
+        let sbar = wad.map_or_else(Sbar::default, |wad| Sbar::new(graphics, &wad, &palette));
+
+        Self {
+            conchars,
+            loading,
+            sbar,
+        }
+    }
+
+    // Draws text with the console font, characters are mapped one to one to the 256 glyphs of the font.
+    pub fn draw_string(&self, graphics: &mut Graphics, x: f32, y: f32, text: &str) {
+        self.draw_string_alpha(graphics, x, y, text, 1.0);
+    }
+
+    pub fn draw_string_alpha(
+        &self,
+        graphics: &mut Graphics,
+        x: f32,
+        y: f32,
+        text: &str,
+        alpha: f32,
+    ) {
+        let Some(conchars) = self.conchars else {
+            return;
+        };
+
+        let mut x = x;
+        for c in text.chars() {
+            let code = u32::from(c).min(255);
+            if code != u32::from(b' ') {
+                #[allow(clippy::cast_precision_loss)]
+                let (col, row) = ((code % 16) as f32 / 16.0, (code / 16) as f32 / 16.0);
+                graphics.draw_sub_picture(
+                    conchars,
+                    [x, y, CHAR_SIZE, CHAR_SIZE],
+                    [col, row, col + 1.0 / 16.0, row + 1.0 / 16.0],
+                    [1.0, 1.0, 1.0, alpha],
+                );
+            }
+            x += CHAR_SIZE;
+        }
+    }
+
+    // Draws the console over the top half of the screen, the output scrolls up from the line being typed.
+    fn draw_console(
+        &self,
+        graphics: &mut Graphics,
+        console: &Console,
+        console_input: &ConsoleInput,
+    ) {
+        let (width, height) = graphics.canvas_size();
+        let console_height = (height / 2.0).floor();
+        graphics.fill_rect([0.0, 0.0, width, console_height], CONSOLE_BACKGROUND);
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let columns = ((width / CHAR_SIZE) as usize).saturating_sub(2).max(2);
+
+        // The line scrolls sideways to keep the cursor in view.
+        let line: Vec<char> = std::iter::once(']')
+            .chain(console_input.line().chars())
+            .collect();
+        let cursor = console_input.cursor() + 1;
+        let offset = (cursor + 1).saturating_sub(columns);
+        let mut y = CHAR_SIZE.mul_add(-2.0, console_height);
+        self.draw_string(
+            graphics,
+            CHAR_SIZE,
+            y,
+            &line[offset..].iter().collect::<String>(),
+        );
+        #[allow(clippy::cast_precision_loss)]
+        let cursor_x = CHAR_SIZE * (cursor - offset + 1) as f32;
+        self.draw_string(graphics, cursor_x, y, &CONSOLE_CURSOR.to_string());
+
+        // Marks that there are more recent lines below the ones shown.
+        if console_input.backscroll() > 0 {
+            y -= CHAR_SIZE;
+            self.draw_string(graphics, CHAR_SIZE, y, &"^".repeat(columns));
+        }
+
+        for (level, text) in console
+            .output()
+            .lines()
+            .rev()
+            .skip(console_input.backscroll())
+        {
+            let chars = console_line(text, level, console_input.search());
+            for row in chars.chunks(columns).rev() {
+                if y < CHAR_SIZE {
+                    return;
+                }
+                y -= CHAR_SIZE;
+                self.draw_string(graphics, CHAR_SIZE, y, &row.iter().collect::<String>());
+            }
+        }
+    }
+
+    // Draws the notify lines at the top of the screen and the centered message a bit above the middle of it.
+    fn draw_hud(&self, graphics: &mut Graphics, hud: &Hud, console: &Console, now: f32) {
+        let (width, height) = graphics.canvas_size();
+
+        // Only the notify lines containing the filter are shown, when there is one.
+        let filter = console
+            .get_cvar("con_notifyfilter")
+            .map(|cvar| cvar.value().to_lowercase())
+            .filter(|filter| !filter.is_empty());
+        let mut y = 0.0;
+        for line in hud.notify_lines(now).filter(|line| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| line.to_lowercase().contains(filter))
+        }) {
+            self.draw_string(graphics, CHAR_SIZE, y, line);
+            y += CHAR_SIZE;
+        }
+
+        if let Some((text, alpha)) = hud.center_text(now) {
+            let mut y = (height * 0.35).floor();
+            for line in text.lines() {
+                #[allow(clippy::cast_precision_loss)]
+                let line_width = line.chars().count() as f32 * CHAR_SIZE;
+                self.draw_string_alpha(
+                    graphics,
+                    ((width - line_width) / 2.0).floor(),
+                    y,
+                    line,
+                    alpha,
+                );
+                y += CHAR_SIZE;
+            }
+        }
+    }
+
+    // Draws the chat message being typed below the notify lines, with a cursor after it.
+    fn draw_chat(&self, graphics: &mut Graphics, text: &str, team: bool) {
+        let prompt = if team { "say_team:" } else { "say:" };
+        #[allow(clippy::cast_precision_loss)]
+        let y = NOTIFY_LINES as f32 * CHAR_SIZE;
+        self.draw_string(
+            graphics,
+            CHAR_SIZE,
+            y,
+            &format!("{prompt} {text}{CONSOLE_CURSOR}"),
+        );
+    }
+
+    // Draws the frame rate and the work done by the last frame in the top right corner, as asked by `cl_showfps` and
+    // `r_speeds`.
+    fn draw_stats(&self, graphics: &mut Graphics, console: &Console, render_time: &RenderTime) {
+        let enabled = |name| console.get_var::<i32>(name).is_some_and(|value| value != 0);
+
+        let mut lines = Vec::new();
+        if enabled("cl_showfps") {
+            lines.push(format!("{:.0} fps", render_time.fps()));
+        }
+        if enabled("r_speeds") {
+            let stats = graphics.stats();
+            lines.push(format!("{:.1} ms", render_time.delta() * 1000.0));
+            lines.push(format!("{} draws {} quads", stats.draw_calls, stats.quads));
+            lines.push(format!("{} uploads", stats.uploads));
+        }
+
+        let (width, _) = graphics.canvas_size();
+        let mut y = 0.0;
+        for line in &lines {
+            #[allow(clippy::cast_precision_loss)]
+            let line_width = line.chars().count() as f32 * CHAR_SIZE;
+            self.draw_string(graphics, width - line_width - CHAR_SIZE, y, line);
+            y += CHAR_SIZE;
+        }
+    }
+
+    fn draw_loading(&self, graphics: &mut Graphics, loading: &Loading) {
+        let (width, height) = graphics.canvas_size();
+
+        let mut y = height / 2.0;
+        if let Some(picture) = self.loading {
+            let (picture_width, picture_height) = graphics.picture_size(picture);
+            y = (height - picture_height) / 2.0;
+            graphics.draw_picture(picture, ((width - picture_width) / 2.0).floor(), y.floor());
+            y += picture_height + CHAR_SIZE;
+        }
+
+        #[allow(clippy::cast_precision_loss)]
+        let title_width = loading.title().chars().count() as f32 * CHAR_SIZE;
+        self.draw_string(
+            graphics,
+            ((width - title_width) / 2.0).floor(),
+            y.floor(),
+            loading.title(),
+        );
+        y += CHAR_SIZE * 2.0;
+
+        let x = ((width - PROGRESS_WIDTH) / 2.0).floor();
+        graphics.fill_rect(
+            [x, y.floor(), PROGRESS_WIDTH, PROGRESS_HEIGHT],
+            PROGRESS_BACKGROUND,
+        );
+        graphics.fill_rect(
+            [
+                x,
+                y.floor(),
+                PROGRESS_WIDTH * loading.progress(),
+                PROGRESS_HEIGHT,
+            ],
+            PROGRESS_FOREGROUND,
+        );
+    }
+}
+
+// Moves ASCII text to the second half of the font, where the glyphs are drawn in an alternate color.
+pub(crate) fn highlight(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_ascii() {
+                char::from(c as u8 | 0x80)
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+// Characters of an output line as drawn by the console.  Warnings and errors use the alternate color, and the matches
+// of the searched text use the other color than the rest of the line.
+fn console_line(text: &str, level: OutputLevel, search: Option<&str>) -> Vec<char> {
+    let mut chars: Vec<char> = match level {
+        OutputLevel::Info => text.chars().collect(),
+        OutputLevel::Warning | OutputLevel::Error => highlight(text).chars().collect(),
+    };
+
+    let Some(search) = search.filter(|search| !search.is_empty()) else {
+        return chars;
+    };
+    let lowercase: Vec<char> = text.to_lowercase().chars().collect();
+    let search: Vec<char> = search.chars().collect();
+    if lowercase.len() != chars.len() {
+        return chars;
+    }
+
+    let mut start = 0;
+    while start + search.len() <= lowercase.len() {
+        if lowercase[start..].starts_with(&search) {
+            for c in &mut chars[start..start + search.len()] {
+                if let Some(flipped) = u8::try_from(*c).ok().map(|c| char::from(c ^ 0x80)) {
+                    *c = flipped;
+                }
+            }
+            start += search.len();
+        } else {
+            start += 1;
+        }
+    }
+
+    chars
+}
+
+// The level starts once everything it needs is loaded, which is handed over to its users.
+#[system]
+pub fn loading_update(
+    #[resource] loading: &mut Option<Loading>,
+    #[resource] sound_registry: &mut SoundRegistry,
+) {
+    let Some(current) = loading else {
+        return;
+    };
+
+    current.sounds.poll();
+    if current.sounds.is_complete() {
+        if let Some(loaded) = loading.take() {
+            *sound_registry = loaded.sounds;
+        }
+    }
+}
+
+#[system]
+pub fn screen_draw(
+    #[resource] screen: &Screen,
+    #[resource] graphics: &mut Graphics,
+    #[resource] loading: &Option<Loading>,
+    #[resource] console: &Console,
+    #[resource] console_input: &ConsoleInput,
+    #[resource] hud: &Hud,
+    #[resource] world_time: &WorldTime,
+) {
+    if let Some(loading) = loading {
+        screen.draw_loading(graphics, loading);
+    } else {
+        if let Some(stats) = hud.stats() {
+            screen.sbar.draw(graphics, screen, stats, world_time.time());
+        }
+        if !console_input.is_open() {
+            screen.draw_hud(graphics, hud, console, world_time.time());
+        }
+    }
+    if console_input.is_open() {
+        screen.draw_console(graphics, console, console_input);
+    }
+}
+
+#[system]
+pub fn render_stats_draw(
+    #[resource] screen: &Screen,
+    #[resource] graphics: &mut Graphics,
+    #[resource] console: &Console,
+    #[resource] render_time: &RenderTime,
+) {
+    screen.draw_stats(graphics, console, render_time);
+}
+
+#[system]
+pub fn chat_draw(
+    #[resource] screen: &Screen,
+    #[resource] graphics: &mut Graphics,
+    #[resource] input: &Input,
+) {
+    if let Some((text, team)) = input.chat_line() {
+        screen.draw_chat(graphics, text, team);
+    }
+}
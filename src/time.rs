@@ -0,0 +1,319 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use legion::system;
+
+use crate::{console::Console, screen::Loading};
+
+// Source of the time elapsed since an arbitrary starting point, anything measuring real time reads it through this
+// so that it can be driven by hand.  The client shares one between the render time, the frame limiter and the audio.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+// Monotonic clock of the system.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+// Clock that only moves when it is told to.  Clones share the same time, so one can be handed over while the other
+// is kept to advance it.
+#[derive(Clone, Default)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    pub fn advance(&self, delta: Duration) {
+        let nanos = u64::try_from(delta.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
+// Wall clock of the client, it measures the time elapsed between rendered frames.
+pub struct RenderTime {
+    clock: Arc<dyn Clock>,
+    elapsed: f32,
+    delta: f32,
+    last_frame: Duration,
+    // Frames rendered since the frame rate was last measured, and the time they took.
+    frames: u32,
+    frame_time: f32,
+    fps: f32,
+}
+
+impl Default for RenderTime {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock::default()))
+    }
+}
+
+impl RenderTime {
+    #[must_use]
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let last_frame = clock.now();
+        Self {
+            clock,
+            elapsed: 0.0,
+            delta: 0.0,
+            last_frame,
+            frames: 0,
+            frame_time: 0.0,
+            fps: 0.0,
+        }
+    }
+
+    #[must_use]
+    pub const fn elapsed(&self) -> f32 {
+        self.elapsed
+    }
+
+    #[must_use]
+    pub const fn delta(&self) -> f32 {
+        self.delta
+    }
+
+    // Frames per second, measured over about a second so that it can be read.
+    #[must_use]
+    pub const fn fps(&self) -> f32 {
+        self.fps
+    }
+
+    pub fn advance(&mut self) {
+        let now = self.clock.now();
+        self.delta = now.saturating_sub(self.last_frame).as_secs_f32();
+        self.elapsed += self.delta;
+        self.last_frame = now;
+
+        // A frame that took no time, such as the first one, doesn't close an interval and isn't counted.
+        if self.delta <= 0.0 {
+            return;
+        }
+        self.frames += 1;
+        self.frame_time += self.delta;
+        if self.frame_time >= 1.0 {
+            #[allow(clippy::cast_precision_loss)]
+            let fps = self.frames as f32 / self.frame_time;
+            self.fps = fps;
+            self.frames = 0;
+            self.frame_time = 0.0;
+        }
+    }
+}
+
+// Holds the frames back to a maximum rate, by sleeping for what is left of the frame time once a frame is done.
+pub struct FrameLimiter {
+    clock: Arc<dyn Clock>,
+    next_frame: Duration,
+}
+
+impl Default for FrameLimiter {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock::default()))
+    }
+}
+
+impl FrameLimiter {
+    #[must_use]
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        let next_frame = clock.now();
+        Self { clock, next_frame }
+    }
+
+    // A rate of 0 leaves the frames unlimited.  A frame that ran late doesn't make the next ones rush to catch up.
+    pub fn wait(&mut self, max_fps: f32) {
+        let now = self.clock.now();
+        if max_fps <= 0.0 {
+            self.next_frame = now;
+            return;
+        }
+
+        if let Some(remaining) = self.next_frame.checked_sub(now) {
+            thread::sleep(remaining);
+        }
+        self.next_frame =
+            self.next_frame.max(now) + Duration::from_secs_f32(1.0 / max_fps.max(10.0));
+    }
+}
+
+// Clock of the world simulation.  The server stamps every block of messages with its time, and the client time runs
+// between the two last stamps so that everything sampled with it moves at the speed of the server.
+#[derive(Default)]
+pub struct WorldTime {
+    client: f32,
+    server: [f32; 2],
+    paused: bool,
+}
+
+impl WorldTime {
+    #[must_use]
+    pub const fn time(&self) -> f32 {
+        self.client
+    }
+
+    #[must_use]
+    pub const fn server_time(&self) -> f32 {
+        self.server[0]
+    }
+
+    #[must_use]
+    pub const fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub const fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    // Pausing freezes the world clock while the client keeps rendering and running console commands.
+    pub const fn advance(&mut self, delta: f32) {
+        if !self.paused {
+            self.client += delta;
+        }
+    }
+
+    pub const fn set_server_time(&mut self, time: f32) {
+        self.server = [time, self.server[0]];
+    }
+
+    // The client has caught up with the server and needs the next block of messages.
+    #[must_use]
+    pub fn needs_update(&self) -> bool {
+        self.client > self.server[0]
+    }
+
+    // Keeps the client time between the two last server times, the same way the original engine does, so that a
+    // late or early block does not make the client run away from the server.
+    pub const fn clamp(&mut self) {
+        let (min, max) = (self.server[1].min(self.server[0]), self.server[0]);
+        self.client = self.client.clamp(min, max);
+    }
+
+    // Returns how far the client time is between the two last server times.
+    #[must_use]
+    pub fn lerp_fraction(&self) -> f32 {
+        let interval = self.server[0] - self.server[1];
+        if interval <= 0.0 {
+            return 1.0;
+        }
+
+        ((self.client - self.server[1]) / interval).clamp(0.0, 1.0)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+fn timescale(console: &Console) -> f32 {
+    console
+        .get_var::<f32>("host_timescale")
+        .filter(|scale| *scale > 0.0)
+        .unwrap_or(1.0)
+}
+
+#[system]
+pub fn time_update(
+    #[resource] render_time: &mut RenderTime,
+    #[resource] world_time: &mut WorldTime,
+    #[resource] console: &Console,
+    #[resource] loading: &Option<Loading>,
+) {
+    render_time.advance();
+    // The world clock waits for the level to be loaded, otherwise the first blocks would be played in a rush.
+    if loading.is_none() {
+        world_time.advance(render_time.delta() * timescale(console));
+    }
+}
+
+#[system]
+pub fn time_command_executor(
+    #[resource] world_time: &mut WorldTime,
+    #[resource] console: &Console,
+) {
+    console.commands().for_each(|command| match &command[..] {
+        // Toggles the pause state of the game.
+        [ref cmd] if cmd == "pause" => {
+            world_time.set_paused(!world_time.is_paused());
+        }
+        _ => (),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_time_follows_the_clock() {
+        let clock = ManualClock::default();
+        let mut render_time = RenderTime::new(Arc::new(clock.clone()));
+
+        render_time.advance();
+        assert_eq!(render_time.delta(), 0.0);
+
+        clock.advance(Duration::from_millis(250));
+        render_time.advance();
+        assert!((render_time.delta() - 0.25).abs() < 1e-6);
+        assert!((render_time.elapsed() - 0.25).abs() < 1e-6);
+        assert_eq!(render_time.fps(), 0.0);
+
+        // Three more frames complete the second the frame rate is measured over, and the next second measures the
+        // same rate.
+        for _ in 0..3 {
+            clock.advance(Duration::from_millis(250));
+            render_time.advance();
+        }
+        assert!((render_time.elapsed() - 1.0).abs() < 1e-6);
+        assert!((render_time.fps() - 4.0).abs() < 1e-4);
+
+        for _ in 0..4 {
+            clock.advance(Duration::from_millis(250));
+            render_time.advance();
+        }
+        assert!((render_time.fps() - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn paused_world_time_stands_still() {
+        let clock = ManualClock::default();
+        let mut render_time = RenderTime::new(Arc::new(clock.clone()));
+        let mut world_time = WorldTime::default();
+
+        clock.advance(Duration::from_millis(100));
+        render_time.advance();
+        world_time.set_paused(true);
+        world_time.advance(render_time.delta());
+        assert_eq!(world_time.time(), 0.0);
+
+        world_time.set_paused(false);
+        world_time.advance(render_time.delta());
+        assert!((world_time.time() - 0.1).abs() < 1e-6);
+    }
+}
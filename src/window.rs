@@ -0,0 +1,175 @@
+use std::sync::Arc;
+
+use legion::system;
+use winit::{
+    dpi::PhysicalSize,
+    monitor::VideoModeHandle,
+    window::{CursorGrabMode, Fullscreen, Icon, Window},
+};
+
+use crate::{
+    client::Connection,
+    console::Console,
+    graphics::Graphics,
+    input::{Input, InputFocus},
+    picture::{Palette, Wad},
+    screen::Loading,
+    time::RenderTime,
+    ResourceFiles,
+};
+
+const TITLE: &str = "quake-rs";
+
+// The face of the status bar, the closest thing to an icon among the game pictures.
+pub fn window_icon(resource_files: &ResourceFiles) -> anyhow::Result<Icon> {
+    let palette = resource_files
+        .take("gfx/palette.lmp")
+        .and_then(Palette::new)?;
+    let picture = resource_files
+        .take("gfx.wad")
+        .and_then(Wad::new)?
+        .picture("face1")?;
+
+    Ok(Icon::from_rgba(
+        picture.to_rgba(&palette),
+        picture.width(),
+        picture.height(),
+    )?)
+}
+
+// Title shown by the window, only set again when it changes.
+#[derive(Default)]
+pub struct WindowTitle {
+    title: String,
+}
+
+#[system]
+pub fn window_update(
+    #[resource] window: &Arc<Window>,
+    #[resource] window_title: &mut WindowTitle,
+    #[resource] console: &Console,
+    #[resource] connection: &Connection,
+    #[resource] loading: &Option<Loading>,
+    #[resource] render_time: &RenderTime,
+) {
+    // The loading progress is always shown, so that it can be followed while the window is in the background.
+    let title = loading.as_ref().map_or_else(
+        || {
+            if console
+                .get_var::<i32>("host_titleinfo")
+                .is_some_and(|info| info != 0)
+            {
+                let level_name = connection.level_name().unwrap_or("no level");
+                format!("{} - {} - {:.0} fps", TITLE, level_name, render_time.fps())
+            } else {
+                TITLE.to_owned()
+            }
+        },
+        |loading| {
+            format!(
+                "{} - loading {} {:.0}%",
+                TITLE,
+                loading.title(),
+                loading.progress() * 100.0
+            )
+        },
+    );
+
+    if title != window_title.title {
+        window.set_title(&title);
+        window_title.title = title;
+    }
+}
+
+// Exclusive fullscreen mode of the monitor the window is on with the given size, the deepest and fastest one if there
+// are several.
+fn video_mode(window: &Window, width: u32, height: u32) -> Option<VideoModeHandle> {
+    window
+        .current_monitor()?
+        .video_modes()
+        .filter(|mode| mode.size() == PhysicalSize::new(width, height))
+        .max_by_key(|mode| (mode.bit_depth(), mode.refresh_rate_millihertz()))
+}
+
+// Applies the video mode set by `vid_width`, `vid_height` and `vid_fullscreen`.  The renderer follows the resize event
+// the window gets afterwards, or is resized right away when the window is.
+fn set_video_mode(
+    window: &Window,
+    graphics: &mut Graphics,
+    console: &Console,
+) -> anyhow::Result<()> {
+    let width = console.get_var::<u32>("vid_width").unwrap_or(0);
+    let height = console.get_var::<u32>("vid_height").unwrap_or(0);
+    if width == 0 || height == 0 {
+        anyhow::bail!("invalid video mode {}x{}", width, height);
+    }
+
+    match console.get_var::<i32>("vid_fullscreen").unwrap_or(0) {
+        0 => {
+            window.set_fullscreen(None);
+            if let Some(size) = window.request_inner_size(PhysicalSize::new(width, height)) {
+                graphics.resize(size.width, size.height);
+            }
+        }
+        1 => window.set_fullscreen(Some(Fullscreen::Borderless(window.current_monitor()))),
+        _ => {
+            let Some(mode) = video_mode(window, width, height) else {
+                window.set_fullscreen(Some(Fullscreen::Borderless(window.current_monitor())));
+                anyhow::bail!(
+                    "no fullscreen mode of {}x{}, using borderless fullscreen",
+                    width,
+                    height
+                );
+            };
+            window.set_fullscreen(Some(Fullscreen::Exclusive(mode)));
+        }
+    }
+
+    Ok(())
+}
+
+#[system]
+pub fn window_command_executor(
+    #[resource] window: &Arc<Window>,
+    #[resource] graphics: &mut Graphics,
+    #[resource] console: &mut Console,
+) {
+    // Changes the video mode to the one set by the video variables.
+    if console
+        .commands()
+        .any(|command| command[0] == "vid_restart")
+    {
+        if let Err(e) = set_video_mode(window, graphics, console) {
+            console.print_warning(&format!("{e:#}"));
+        }
+    }
+}
+
+// Keeps the cursor hidden and held in the window while the mouse turns the view, it is given back while the console
+// is open.  Some platforms can only confine the cursor to the window instead of locking it in place.  Input methods
+// are only enabled while a line is typed, so that they don't take the keys bound to game commands.
+#[system]
+pub fn cursor_update(
+    #[state] focus: &mut Option<InputFocus>,
+    #[resource] window: &Arc<Window>,
+    #[resource] input: &Input,
+) {
+    if *focus == Some(input.focus()) {
+        return;
+    }
+    *focus = Some(input.focus());
+
+    let grab = input.focus() != InputFocus::Console;
+    let result = if grab {
+        window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+    } else {
+        window.set_cursor_grab(CursorGrabMode::None)
+    };
+    if let Err(e) = result {
+        tracing::warn!("couldn't grab the cursor: {}", e);
+    }
+    window.set_cursor_visible(!grab);
+    window.set_ime_allowed(input.focus() != InputFocus::Game);
+}